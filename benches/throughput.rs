@@ -0,0 +1,100 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, SamplingMode, Throughput};
+use std::env;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc::{channel, unbounded_channel};
+use toy_engine::csv_parser::{deserialize_csv, Admission, EventOrdering, InputFormat, Transaction, TransactionMessage, TransactionStatus};
+use toy_engine::fee::FeeSchedule;
+use toy_engine::generate::{self, Generator, GeneratorOptions};
+use toy_engine::transaction_manager::TransactionManager;
+
+// Row counts of every benchmark, overridden with a comma separated TOY_ENGINE_BENCH_ROWS for quicker runs.
+fn sizes() -> Vec<u32> {
+    match env::var("TOY_ENGINE_BENCH_ROWS") {
+        Ok(rows) => rows.split(',').map(|size| size.trim().parse().expect("invalid TOY_ENGINE_BENCH_ROWS")).collect(),
+        Err(_) => vec![1_000_000, 10_000_000],
+    }
+}
+
+fn generator(transactions: u32) -> Generator {
+    Generator::new(GeneratorOptions { transactions: transactions.into(), ..GeneratorOptions::default() })
+}
+
+fn csv(runtime: &Runtime, transactions: u32) -> Vec<u8> {
+    let mut output = Vec::new();
+    runtime.block_on(generate::write(&mut output, generator(transactions))).unwrap();
+    output
+}
+
+fn deserialize(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("deserialize_csv");
+    group.sample_size(10).sampling_mode(SamplingMode::Flat).measurement_time(Duration::from_secs(10));
+    for size in sizes() {
+        let input = csv(&runtime, size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &input, |b, input| {
+            b.iter(|| runtime.block_on(async {
+                let (tx, mut rx) = channel::<TransactionMessage>(100);
+                let consumer = async {
+                    while let Some(message) = rx.recv().await {
+                        let _ = message.sender.send(Ok(TransactionStatus::Applied));
+                    }
+                };
+                let (parsed, _) = tokio::join!(deserialize_csv(tx, unbounded_channel().0, input.as_slice(), InputFormat::default(), EventOrdering::default(), Admission::default()), consumer);
+                parsed.unwrap();
+            }));
+        });
+    }
+    group.finish();
+}
+
+fn process(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("process_transaction");
+    group.sample_size(10).sampling_mode(SamplingMode::Flat).measurement_time(Duration::from_secs(10));
+    for size in sizes() {
+        let transactions: Vec<Transaction> = generator(size).collect();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &transactions, |b, transactions| {
+            b.iter(|| runtime.block_on(async {
+                let mut manager = TransactionManager::new(FeeSchedule::empty());
+                for transaction in transactions {
+                    let _ = manager.process_transaction(transaction).await;
+                }
+                manager
+            }));
+        });
+    }
+    group.finish();
+}
+
+// The released binary end to end, from the input file to the account report, with logging reduced to errors.
+fn pipeline(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("pipeline");
+    group.sample_size(10).sampling_mode(SamplingMode::Flat).measurement_time(Duration::from_secs(30));
+    for size in sizes() {
+        let path = env::temp_dir().join(format!("toy_engine_bench_{}_{}.csv", size, std::process::id()));
+        std::fs::write(&path, csv(&runtime, size)).unwrap();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &path, |b, path| {
+            b.iter(|| {
+                let status = Command::new(env!("CARGO_BIN_EXE_toy_engine"))
+                    .arg(path)
+                    .env("TOY_ENGINE_LOG", "error")
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+            });
+        });
+        std::fs::remove_file(&path).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, deserialize, process, pipeline);
+criterion_main!(benches);
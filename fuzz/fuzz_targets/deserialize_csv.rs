@@ -0,0 +1,22 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use tokio::sync::mpsc::{channel, unbounded_channel};
+use toy_engine::csv_parser::{deserialize_csv, Admission, EventOrdering, InputFormat, LatePolicy, TransactionStatus};
+
+// The first byte picks the reorder buffer, the rest is the input file; every row must be parsed or reported.
+fuzz_target!(|data: &[u8]| {
+    let (buffer, input) = match data.split_first() {
+        Some((buffer, input)) => (*buffer as usize % 4, input.to_vec()),
+        None => (0, Vec::new()),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let result = runtime.block_on(async move {
+        let (tx, mut rx) = channel(4);
+        let parser = tokio::spawn(deserialize_csv(tx, unbounded_channel().0, std::io::Cursor::new(input), InputFormat::default(), EventOrdering { buffer, late_policy: LatePolicy::Reject }, Admission::default()));
+        while let Some(message) = rx.recv().await {
+            let _ = message.sender.send(Ok(TransactionStatus::Applied));
+        }
+        parser.await.unwrap()
+    });
+    assert!(result.is_ok());
+});
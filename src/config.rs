@@ -0,0 +1,348 @@
+use crate::directory::UnknownClientPolicy;
+use crate::fee::DEFAULT_HOUSE_ACCOUNT;
+use crate::ids::ClientId;
+use crate::options::{Options, parse_addr, parse_delimiter, parse_precision, parse_queue_depth};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Duration;
+
+// TOML configuration file, every setting optional. Command line flags override it, `print-config` shows the result.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub input: InputConfig,
+    pub output: OutputConfig,
+    pub engine: EngineConfig,
+    pub fees: FeesConfig,
+    pub limits: LimitsConfig,
+    pub accounts: AccountsConfig,
+    pub rules: RulesConfig,
+    pub storage: StorageConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InputConfig {
+    pub file: Option<String>,
+    pub delimiter: Option<String>,
+    pub precision: Option<u32>,
+    pub rounding: Option<String>,
+    pub reorder_buffer: Option<usize>,
+    pub late_policy: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    pub delimiter: Option<String>,
+    pub last_activity: Option<bool>,
+    pub ledger: Option<String>,
+    pub fee_ledger: Option<String>,
+    pub alerts: Option<String>,
+    pub history: Option<String>,
+    pub events: Option<String>,
+    pub events_format: Option<String>,
+    pub outcomes: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub dispute_policy: Option<String>,
+    pub lock_policy: Option<String>,
+    pub audit: Option<bool>,
+    pub queue_depth: Option<usize>,
+    pub admission: Option<String>,
+    pub result_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeesConfig {
+    pub file: Option<String>,
+    pub house_account: Option<ClientId>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub file: Option<String>,
+    pub tiers: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    pub file: Option<String>,
+    pub unknown_clients: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub metrics_addr: Option<String>,
+    pub metrics_file: Option<String>,
+}
+
+// Accounts, transactions and the ledger are kept in memory, there is no other backend yet.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StorageBackend {
+    Memory,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(format!("Unsupported storage backend {}, expected memory", backend)),
+        }
+    }
+}
+
+fn setting<T, E: std::fmt::Display>(section: &str, key: &str, value: &Option<String>, parse: impl Fn(&str) -> Result<T, E>) -> Result<Option<T>, String> {
+    match value {
+        Some(value) => parse(value).map(Some).map_err(|err| format!("[{}] {}: {}", section, key, err)),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    pub async fn load(path: &str) -> Result<Config, String> {
+        let text = tokio::fs::read_to_string(path).await.map_err(|err| format!("Cannot read configuration {}: {}", path, err))?;
+        Config::parse(&text).map_err(|err| format!("Invalid configuration {}: {}", path, err))
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|err| err.to_string())
+    }
+
+    // Every value is checked as its command line flag would be before any of them is applied.
+    pub fn apply(&self, options: &mut Options) -> Result<(), String> {
+        let input = &self.input;
+        let input_delimiter = setting("input", "delimiter", &input.delimiter, parse_delimiter)?;
+        let precision = match input.precision {
+            Some(precision) => Some(parse_precision(&precision.to_string()).map_err(|err| format!("[input] precision: {}", err))?),
+            None => None,
+        };
+        let rounding = setting("input", "rounding", &input.rounding, str::parse)?;
+        let late_policy = setting("input", "late_policy", &input.late_policy, str::parse)?;
+
+        let output = &self.output;
+        let output_delimiter = setting("output", "delimiter", &output.delimiter, parse_delimiter)?;
+        let events_format = setting("output", "events_format", &output.events_format, str::parse)?;
+
+        let engine = &self.engine;
+        let dispute_policy = setting("engine", "dispute_policy", &engine.dispute_policy, str::parse)?;
+        let lock_policy = setting("engine", "lock_policy", &engine.lock_policy, str::parse)?;
+        let admission = setting("engine", "admission", &engine.admission, str::parse)?;
+        let queue_depth = match engine.queue_depth {
+            Some(size) => Some(parse_queue_depth(&size.to_string()).map_err(|err| format!("[engine] queue_depth: {}", err))?),
+            None => None,
+        };
+
+        let unknown_clients = setting("accounts", "unknown_clients", &self.accounts.unknown_clients, UnknownClientPolicy::from_str)?;
+
+        setting("storage", "backend", &self.storage.backend, StorageBackend::from_str)?;
+        let metrics_addr = setting("server", "metrics_addr", &self.server.metrics_addr, parse_addr)?;
+
+        if let Some(file) = &input.file { options.input = file.clone(); }
+        if let Some(delimiter) = input_delimiter { options.input_format.delimiter = delimiter; }
+        if let Some(precision) = precision { options.input_format.precision = precision; }
+        if let Some(rounding) = rounding { options.input_format.rounding = rounding; }
+        if let Some(buffer) = input.reorder_buffer { options.ordering.buffer = buffer; }
+        if let Some(late_policy) = late_policy { options.ordering.late_policy = late_policy; }
+
+        if let Some(delimiter) = output_delimiter { options.output_delimiter = delimiter; }
+        if let Some(last_activity) = output.last_activity { options.last_activity = last_activity; }
+        options.ledger = output.ledger.clone().or(options.ledger.take());
+        options.fee_ledger = output.fee_ledger.clone().or(options.fee_ledger.take());
+        options.alerts = output.alerts.clone().or(options.alerts.take());
+        options.history = output.history.clone().or(options.history.take());
+        options.events = output.events.clone().or(options.events.take());
+        if let Some(events_format) = events_format { options.events_format = events_format; }
+        options.outcomes = output.outcomes.clone().or(options.outcomes.take());
+
+        if let Some(dispute_policy) = dispute_policy { options.dispute_policy = dispute_policy; }
+        if let Some(lock_policy) = lock_policy { options.lock_policy = lock_policy; }
+        if let Some(audit) = engine.audit { options.audit = audit; }
+        if let Some(queue_depth) = queue_depth { options.queue_depth = queue_depth; }
+        if let Some(admission) = admission { options.admission.policy = admission; }
+        if let Some(millis) = engine.result_timeout { options.admission.result_timeout = Some(Duration::from_millis(millis)); }
+
+        options.fees = self.fees.file.clone().or(options.fees.take());
+        if let Some(house_account) = self.fees.house_account { options.house_account = house_account; }
+        options.limits = self.limits.file.clone().or(options.limits.take());
+        options.tiers = self.limits.tiers.clone().or(options.tiers.take());
+        options.accounts = self.accounts.file.clone().or(options.accounts.take());
+        if let Some(unknown_clients) = unknown_clients { options.unknown_clients = unknown_clients; }
+        options.rules = self.rules.file.clone().or(options.rules.take());
+        options.metrics_addr = metrics_addr.or(options.metrics_addr.take());
+        options.metrics_file = self.server.metrics_file.clone().or(options.metrics_file.take());
+        Ok(())
+    }
+
+    // Every setting as the engine will use it, unset files left out.
+    pub fn effective(options: &Options) -> Config {
+        Config {
+            input: InputConfig {
+                file: Some(options.input.clone()).filter(|input| !input.is_empty()),
+                delimiter: Some((options.input_format.delimiter as char).to_string()),
+                precision: Some(options.input_format.precision),
+                rounding: Some(options.input_format.rounding.to_string()),
+                reorder_buffer: Some(options.ordering.buffer),
+                late_policy: Some(options.ordering.late_policy.to_string()),
+            },
+            output: OutputConfig {
+                delimiter: Some((options.output_delimiter as char).to_string()),
+                last_activity: Some(options.last_activity),
+                ledger: options.ledger.clone(),
+                fee_ledger: options.fee_ledger.clone(),
+                alerts: options.alerts.clone(),
+                history: options.history.clone(),
+                events: options.events.clone(),
+                events_format: Some(options.events_format.to_string()),
+                outcomes: options.outcomes.clone(),
+            },
+            engine: EngineConfig {
+                dispute_policy: Some(options.dispute_policy.to_string()),
+                lock_policy: Some(options.lock_policy.to_string()),
+                audit: Some(options.audit),
+                queue_depth: Some(options.queue_depth),
+                admission: Some(options.admission.policy.to_string()),
+                result_timeout: options.admission.result_timeout.map(|timeout| timeout.as_millis() as u64),
+            },
+            // The default house account is left out, a string id build has no text that reads back as it.
+            fees: FeesConfig { file: options.fees.clone(), house_account: Some(options.house_account).filter(|account| *account != DEFAULT_HOUSE_ACCOUNT) },
+            limits: LimitsConfig { file: options.limits.clone(), tiers: options.tiers.clone() },
+            accounts: AccountsConfig { file: options.accounts.clone(), unknown_clients: Some(options.unknown_clients.to_string()) },
+            rules: RulesConfig { file: options.rules.clone() },
+            storage: StorageConfig { backend: Some("memory".to_owned()) },
+            server: ServerConfig { metrics_addr: options.metrics_addr.clone(), metrics_file: options.metrics_file.clone() },
+        }
+    }
+
+    pub fn to_toml(&self) -> Result<String, String> {
+        toml::to_string(self).map_err(|err| err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::csv_parser::{AdmissionPolicy, LatePolicy, Rounding};
+    use crate::events::EventFormat;
+    use crate::ids::client_id;
+    use crate::options::parse;
+    use crate::transaction_manager::{DisputePolicy, LockPolicy};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_config_file_and_overrides() {
+        let options = parse(&args(&["--config", "test/config.toml"])).await.unwrap();
+        assert_eq!(options.input, "test/parse.csv");
+        assert_eq!(options.input_format.delimiter, b';');
+        assert_eq!(options.input_format.precision, 2);
+        assert_eq!(options.input_format.rounding, Rounding::Round);
+        assert_eq!(options.ordering.buffer, 4);
+        assert_eq!(options.ordering.late_policy, LatePolicy::Drop);
+        assert_eq!(options.output_delimiter, b'\t');
+        assert!(options.last_activity);
+        assert_eq!(options.events_format, EventFormat::Jsonl);
+        assert_eq!(options.dispute_policy, DisputePolicy::Cap);
+        assert_eq!(options.lock_policy, LockPolicy::Outgoing);
+        assert!(options.audit);
+        assert_eq!(options.queue_depth, 32);
+        assert_eq!(options.admission.policy, AdmissionPolicy::Shed);
+        assert_eq!(options.admission.result_timeout, Some(Duration::from_millis(500)));
+        assert_eq!(options.fees.as_deref(), Some("test/fees.csv"));
+        assert_eq!(options.house_account, client_id(999));
+        assert_eq!(options.limits.as_deref(), Some("test/limits.csv"));
+        assert_eq!(options.tiers.as_deref(), Some("test/tiers.csv"));
+        assert_eq!(options.accounts.as_deref(), Some("test/accounts.csv"));
+        assert_eq!(options.unknown_clients, UnknownClientPolicy::Reject);
+        assert_eq!(options.metrics_addr.as_deref(), Some("127.0.0.1:9898"));
+
+        let options = parse(&args(&["other.csv", "--dispute-policy", "reject", "--config", "test/config.toml", "--precision", "4", "--house-account", "7"])).await.unwrap();
+        assert_eq!(options.input, "other.csv");
+        assert_eq!(options.dispute_policy, DisputePolicy::Reject);
+        assert_eq!(options.input_format.precision, 4);
+        assert_eq!(options.input_format.rounding, Rounding::Round);
+        assert_eq!(options.house_account, client_id(7));
+    }
+
+    #[tokio::test]
+    async fn test_config_errors() {
+        let mut options = Options::default();
+        for (text, error) in [
+            ("[input]\nseparator = \";\"", "unknown field `separator`"),
+            ("[engine]\nqueue_depth = \"many\"", "invalid type"),
+            ("[input]\ndelimiter = \";;\"", "[input] delimiter"),
+            ("[input]\nprecision = 12", "[input] precision"),
+            ("[engine]\ndispute_policy = \"never\"", "[engine] dispute_policy"),
+            ("[engine]\nqueue_depth = 0", "[engine] queue_depth"),
+            ("[storage]\nbackend = \"postgres\"", "[storage] backend: Unsupported storage backend postgres"),
+            ("[server]\nmetrics_addr = \"9898\"", "[server] metrics_addr"),
+            ("[accounts]\nunknown_clients = \"ignore\"", "[accounts] unknown_clients"),
+        ].iter() {
+            let err = Config::parse(text).and_then(|config| config.apply(&mut options)).unwrap_err();
+            assert!(err.contains(error), "{}: {}", text, err);
+        }
+        assert_eq!(options.queue_depth, crate::options::DEFAULT_QUEUE_DEPTH);
+
+        assert!(parse(&args(&["transactions.csv", "--config", "test/missing.toml"])).await.unwrap_err().starts_with("Cannot read configuration"));
+        assert!(parse(&args(&["transactions.csv", "--config"])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_config_cross_checks_follow_overrides() {
+        for (name, text, flag, file, error) in [
+            ("accounts", "[accounts]\nunknown_clients = \"reject\"\n", "--accounts", "test/accounts.csv", "requires --accounts"),
+            ("limits", "[limits]\ntiers = \"test/tiers.csv\"\n", "--limits", "test/limits.csv", "requires --limits"),
+        ].iter() {
+            let path = std::env::temp_dir().join(format!("toy_engine_config_{}_{}.toml", name, std::process::id()));
+            tokio::fs::write(&path, text).await.unwrap();
+            let path = path.to_str().unwrap();
+
+            let options = parse(&args(&["transactions.csv", "--config", path, flag, file])).await.unwrap();
+            assert!(options.accounts.is_some() || options.limits.is_some());
+            let err = parse(&args(&["transactions.csv", "--config", path])).await.unwrap_err();
+            assert!(err.contains(error), "{}: {}", name, err);
+            tokio::fs::remove_file(path).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_effective_config_round_trip() {
+        let options = parse(&args(&["--config", "test/config.toml", "--outcomes", "outcomes.csv"])).await.unwrap();
+        let printed = Config::effective(&options).to_toml().unwrap();
+        assert!(printed.contains("outcomes = \"outcomes.csv\""));
+        assert!(printed.contains("backend = \"memory\""));
+
+        let mut reloaded = Options::default();
+        Config::parse(&printed).unwrap().apply(&mut reloaded).unwrap();
+        assert_eq!(Config::effective(&reloaded), Config::effective(&options));
+
+        let defaults = Config::effective(&Options::default());
+        assert_eq!(defaults.input.file, None);
+        assert_eq!(defaults.fees.file, None);
+        assert_eq!(defaults.fees.house_account, None);
+    }
+}
@@ -1,12 +1,24 @@
-use serde::{Deserialize, Deserializer};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error as DeError;
 use tokio::io::AsyncRead;
 use csv_async::{AsyncReaderBuilder, Trim};
-use futures::stream::StreamExt;
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesOrdered, StreamExt};
 use tokio::sync::oneshot;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tokio::sync::mpsc::error::TrySendError;
+use std::time::Duration;
 use std::fmt::{Debug, Formatter, Display};
 use std::error::Error;
+use std::cmp::{Ordering, Reverse};
+use std::convert::TryFrom;
+use std::collections::BinaryHeap;
+use std::str::FromStr;
+use chrono::{DateTime, SecondsFormat, Utc};
+use tracing::{info, info_span, warn, Instrument, Span};
+use crate::ids::{self, ClientId, TxId};
 
-#[derive(Deserialize, Debug,Copy,Clone)]
+#[derive(Deserialize, Serialize, Debug,Copy,Clone,PartialEq,Eq,Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -14,31 +26,144 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     ChargeBack,
+    Transfer,
+    Reversal,
+    Open,
+    Close,
+    Suspend,
+    Reactivate,
 }
 
-fn custom_precision_deserialize<'de, D>(de: D) -> Result<Option<f64>, D::Error>
+impl TransactionType {
+    // Operator changes of the account status, they move no funds.
+    pub fn is_lifecycle(&self) -> bool {
+        matches!(self, TransactionType::Open | TransactionType::Close | TransactionType::Suspend | TransactionType::Reactivate)
+    }
+
+    // Deposits, withdrawals and transfers bring a tx id of their own, the other types reference an existing one.
+    pub fn is_new_transaction(&self) -> bool {
+        matches!(self, TransactionType::Deposit | TransactionType::WithDrawal | TransactionType::Transfer)
+    }
+}
+
+// NaN and infinities would poison every balance they touch, the precision is applied by the InputFormat.
+fn amount_deserialize<'de, D>(de: D) -> Result<Option<f64>, D::Error>
     where
         D: Deserializer<'de>,
 {
     let dec:Option<f64> = Option::deserialize(de)?;
 
-    if let Some(dec) = dec
-    {
-        let trunc : f64 = (dec * 10000.0).trunc() / 10000.0;
-        return Ok(Some(trunc));
-    } else {
-        Ok(None)
+    match dec {
+        Some(dec) if !dec.is_finite() => Err(D::Error::custom(format!("amount {} is not a finite number", dec))),
+        _ => Ok(dec),
+    }
+}
+
+pub const DEFAULT_PRECISION: u32 = 4;
+pub const MAX_PRECISION: u32 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Rounding {
+    Truncate,
+    Round,
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(rounding: &str) -> Result<Self, Self::Err> {
+        match rounding {
+            "truncate" => Ok(Rounding::Truncate),
+            "round" => Ok(Rounding::Round),
+            _ => Err(format!("Unknown rounding {}, expected truncate or round", rounding)),
+        }
     }
 }
 
-#[derive(Deserialize, Debug)]
+impl Display for Rounding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rounding::Truncate => {write!(f, "truncate")}
+            Rounding::Round => {write!(f, "round")}
+        }
+    }
+}
+
+// Field delimiter of the input file and the number of decimals amounts are cut to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InputFormat {
+    pub delimiter: u8,
+    pub precision: u32,
+    pub rounding: Rounding,
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        InputFormat { delimiter: b',', precision: DEFAULT_PRECISION, rounding: Rounding::Truncate }
+    }
+}
+
+impl InputFormat {
+    // None when the amount is too large to keep the requested decimals.
+    pub fn amount(&self, amount: f64) -> Option<f64> {
+        let scale = 10f64.powi(self.precision as i32);
+        let scaled = match self.rounding {
+            Rounding::Truncate => (amount * scale).trunc(),
+            Rounding::Round => (amount * scale).round(),
+        };
+        Some(scaled / scale).filter(|amount| amount.is_finite())
+    }
+}
+
+// Timestamps are epoch millis or RFC 3339 date-times, stored as epoch millis.
+pub fn parse_timestamp(value: &str) -> Result<u64, String> {
+    if let Ok(millis) = value.parse::<u64>() {
+        return Ok(millis)
+    }
+    let date_time = DateTime::parse_from_rfc3339(value).map_err(|err| format!("Invalid timestamp {}: {}", value, err))?;
+    u64::try_from(date_time.timestamp_millis()).map_err(|_| format!("Timestamp before 1970 {}", value))
+}
+
+pub fn format_timestamp(millis: u64) -> String {
+    match i64::try_from(millis).ok().and_then(DateTime::<Utc>::from_timestamp_millis) {
+        Some(date_time) => date_time.to_rfc3339_opts(SecondsFormat::Millis, true),
+        None => millis.to_string(),
+    }
+}
+
+fn timestamp_deserialize<'de, D>(de: D) -> Result<Option<u64>, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(de)?;
+    match value {
+        Some(value) => parse_timestamp(&value).map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Transaction {
-    #[serde(alias = "type")]
+    #[serde(rename = "type", alias = "trans_type")]
     pub trans_type: TransactionType,
-    pub client: u16,
-    pub tx: u32,
-    #[serde(deserialize_with = "custom_precision_deserialize")]
-    pub amount: Option<f64>
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(deserialize_with = "amount_deserialize")]
+    pub amount: Option<f64>,
+    #[serde(default)]
+    pub destination: Option<ClientId>,
+    #[serde(default, deserialize_with = "timestamp_deserialize")]
+    pub timestamp: Option<u64>,
+    #[serde(skip)]
+    pub sequence: u64,
+}
+
+impl Transaction {
+    // Time used by windowed rules: the timestamp column when present, the row sequence number otherwise. The reader
+    // rejects input mixing both, so times of one input are always in the same unit.
+    pub fn time(&self) -> u64 {
+        self.timestamp.unwrap_or(self.sequence)
+    }
 }
 
 impl ToOwned for Transaction {
@@ -50,10 +175,20 @@ impl ToOwned for Transaction {
             client: self.client,
             tx: self.tx,
             amount: self.amount,
+            destination: self.destination,
+            timestamp: self.timestamp,
+            sequence: self.sequence,
         }
     }
 }
 
+// An exact resubmission of an applied transaction is acknowledged as a duplicate without being applied again.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TransactionStatus {
+    Applied,
+    Duplicate,
+}
+
 #[derive(Debug)]
 pub enum TransactionError {
     InsufficientFund,
@@ -61,6 +196,28 @@ pub enum TransactionError {
     ReferencedTransactionIsNotDisputed,
     NoAmountForTransaction,
     ExistingTransactionId,
+    InvalidTransferDestination,
+    InvalidTransferAmount,
+    AccountLocked,
+    TransactionNotDisputable,
+    TransactionNotReversible,
+    InsufficientFundForDispute,
+    WithdrawalLimitExceeded,
+    WindowWithdrawalLimitExceeded,
+    TransactionRateExceeded,
+    BlockedByRule(&'static str),
+    LateTransaction,
+    MixedTimestamps,
+    QueueFull,
+    UnknownClient,
+    AccountSuspended,
+    AccountClosed,
+    AccountAlreadyOpen,
+    AccountNotFound,
+    AccountBalanceNotZero,
+    AccountAlreadySuspended,
+    AccountNotSuspended,
+    HouseAccount,
 }
 
 impl Display for TransactionError {
@@ -71,51 +228,332 @@ impl Display for TransactionError {
             TransactionError::ReferencedTransactionIsNotDisputed => {write!(f, "Referenced transaction not under dispute")}
             TransactionError::NoAmountForTransaction => {write!(f, "Invalid transaction, it doesn't have amount")}
             TransactionError::ExistingTransactionId => {write!(f, "Transaction id is already exists")}
+            TransactionError::InvalidTransferDestination => {write!(f, "Transfer has no valid destination client")}
+            TransactionError::InvalidTransferAmount => {write!(f, "Transfer amount must be positive")}
+            TransactionError::HouseAccount => {write!(f, "Client is the house account collecting the fees")}
+            TransactionError::AccountLocked => {write!(f, "Account is locked")}
+            TransactionError::TransactionNotDisputable => {write!(f, "Referenced transaction cannot be disputed by this client")}
+            TransactionError::TransactionNotReversible => {write!(f, "Only undisputed deposits and withdrawals can be reversed")}
+            TransactionError::InsufficientFundForDispute => {write!(f, "No available fund to hold for the dispute")}
+            TransactionError::WithdrawalLimitExceeded => {write!(f, "Withdrawal exceeds the single withdrawal limit")}
+            TransactionError::WindowWithdrawalLimitExceeded => {write!(f, "Withdrawal exceeds the withdrawal limit of the window")}
+            TransactionError::TransactionRateExceeded => {write!(f, "Too many transactions in the window")}
+            TransactionError::BlockedByRule(rule) => {write!(f, "Transaction blocked by rule {}", rule)}
+            TransactionError::LateTransaction => {write!(f, "Transaction arrived later than the reorder buffer allows")}
+            TransactionError::MixedTimestamps => {write!(f, "Input mixes rows with and without timestamp")}
+            TransactionError::QueueFull => {write!(f, "Transaction queue is full, the transaction was shed")}
+            TransactionError::UnknownClient => {write!(f, "Client is not in the accounts master file")}
+            TransactionError::AccountSuspended => {write!(f, "Account is suspended")}
+            TransactionError::AccountClosed => {write!(f, "Account is closed")}
+            TransactionError::AccountAlreadyOpen => {write!(f, "Account is already open")}
+            TransactionError::AccountNotFound => {write!(f, "Account does not exist")}
+            TransactionError::AccountBalanceNotZero => {write!(f, "Only an account with no available or held funds can be closed")}
+            TransactionError::AccountAlreadySuspended => {write!(f, "Account is already suspended")}
+            TransactionError::AccountNotSuspended => {write!(f, "Only a suspended account can be reactivated")}
         }
     }
 }
 
 impl Error for TransactionError{}
 
+// A row the reader already rejected is still sent with its `rejection`, the manager only reports it.
 pub struct TransactionMessage {
     pub transaction: Transaction,
-    pub sender: oneshot::Sender<Result<(), TransactionError>>
+    pub rejection: Option<TransactionError>,
+    pub sender: oneshot::Sender<Result<TransactionStatus, TransactionError>>
 }
 
-pub async fn deserialize_csv(tx: tokio::sync::mpsc::Sender<TransactionMessage>, reader: impl AsyncRead + Unpin + Send + Sync)
+pub async fn deserialize_records<T>(reader: impl AsyncRead + Unpin + Send + Sync) -> Result<Vec<T>, csv_async::Error>
+    where T: for<'de> Deserialize<'de> + 'static
+{
+    let mut deserializer = AsyncReaderBuilder::new()
+        .trim(Trim::All)
+        .create_deserializer(reader);
+    let mut records = deserializer.deserialize::<T>();
+
+    let mut rows = Vec::new();
+    while let Some(record) = records.next().await {
+        rows.push(record?);
+    }
+    Ok(rows)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LatePolicy {
+    Accept,
+    Drop,
+    Reject,
+}
+
+impl FromStr for LatePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "accept" => Ok(LatePolicy::Accept),
+            "drop" => Ok(LatePolicy::Drop),
+            "reject" => Ok(LatePolicy::Reject),
+            _ => Err(format!("Unknown late policy {}, expected accept, drop or reject", policy)),
+        }
+    }
+}
+
+impl Display for LatePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LatePolicy::Accept => {write!(f, "accept")}
+            LatePolicy::Drop => {write!(f, "drop")}
+            LatePolicy::Reject => {write!(f, "reject")}
+        }
+    }
+}
+
+// Up to `buffer` records are held back and released in event-time order. A record older than the
+// last released one is late and handled by `late_policy`.
+#[derive(Debug, Copy, Clone)]
+pub struct EventOrdering {
+    pub buffer: usize,
+    pub late_policy: LatePolicy,
+}
+
+impl Default for EventOrdering {
+    fn default() -> Self {
+        EventOrdering { buffer: 0, late_policy: LatePolicy::Accept }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AdmissionPolicy {
+    Block,
+    Shed,
+}
+
+impl FromStr for AdmissionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "block" => Ok(AdmissionPolicy::Block),
+            "shed" => Ok(AdmissionPolicy::Shed),
+            _ => Err(format!("Unknown admission policy {}, expected block or shed", policy)),
+        }
+    }
+}
+
+impl Display for AdmissionPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdmissionPolicy::Block => {write!(f, "block")}
+            AdmissionPolicy::Shed => {write!(f, "shed")}
+        }
+    }
+}
+
+// How the ingestion source hands rows to the manager: a full queue either blocks the source or sheds the row,
+// and the result of a row is awaited at most `result_timeout`. The engine reads a single source, the input file,
+// so there is one admission for the whole run.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Admission {
+    pub policy: AdmissionPolicy,
+    pub result_timeout: Option<Duration>,
+}
+
+impl Default for Admission {
+    fn default() -> Self {
+        Admission { policy: AdmissionPolicy::Block, result_timeout: None }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IngestionError {
+    ChannelClosed,
+    ResultDropped,
+    ResultTimeout,
+}
+
+impl Display for IngestionError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IngestionError::ChannelClosed => {write!(f, "Transaction channel is closed")}
+            IngestionError::ResultDropped => {write!(f, "Transaction manager dropped the result")}
+            IngestionError::ResultTimeout => {write!(f, "Transaction result timed out")}
+        }
+    }
+}
+
+impl Error for IngestionError {}
+
+struct Buffered(Transaction);
+
+impl Buffered {
+    fn key(&self) -> (u64, u64) {
+        (self.0.time(), self.0.sequence)
+    }
+}
+
+impl PartialEq for Buffered {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Buffered {}
+
+impl PartialOrd for Buffered {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Buffered {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+fn row_span(transaction: &Transaction) -> tracing::Span {
+    info_span!("row", row = transaction.sequence, client = ids::field(transaction.client), tx = ids::field(transaction.tx), r#type = ?transaction.trans_type)
+}
+
+// Results still to come, in row order; they are collected while the next rows are read so that the queue can fill up.
+type Pending = FuturesOrdered<BoxFuture<'static, ()>>;
+
+// Only a closed channel stops the reader; a late or a dropped result is logged and reading goes on. A shed row goes
+// to the manager on the unbounded `shed` channel instead, rejected as `QueueFull`, so that it still has an outcome.
+async fn submit(tx: &Sender<TransactionMessage>, shed: &UnboundedSender<TransactionMessage>, transaction: Transaction, rejection: Option<TransactionError>, admission: Admission, pending: &mut Pending) -> Result<(), IngestionError> {
+    let (otx, orx) = oneshot::channel::<Result<TransactionStatus, TransactionError>>();
+
+    let message = TransactionMessage {
+        transaction,
+        rejection,
+        sender: otx,
+    };
+
+    match admission.policy {
+        AdmissionPolicy::Block => tx.send(message).await.map_err(|_| IngestionError::ChannelClosed)?,
+        AdmissionPolicy::Shed => match tx.try_send(message) {
+            Ok(()) => {},
+            Err(TrySendError::Full(mut message)) => {
+                message.rejection = Some(TransactionError::QueueFull);
+                shed.send(message).map_err(|_| IngestionError::ChannelClosed)?;
+            },
+            Err(TrySendError::Closed(_)) => return Err(IngestionError::ChannelClosed),
+        },
+    }
+
+    pending.push_back(report(orx, admission.result_timeout).instrument(Span::current()).boxed());
+    Ok(())
+}
+
+async fn report(orx: oneshot::Receiver<Result<TransactionStatus, TransactionError>>, timeout: Option<Duration>) {
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, orx).await.map_err(|_| IngestionError::ResultTimeout),
+        None => Ok(orx.await),
+    };
+    match result {
+        Ok(Ok(Ok(TransactionStatus::Applied))) => {},
+        Ok(Ok(Ok(TransactionStatus::Duplicate))) => info!("duplicate acknowledged"),
+        Ok(Ok(Err(err))) => warn!(error = ?err, "transaction rejected"),
+        Ok(Err(_)) => warn!(error = %IngestionError::ResultDropped, "no transaction result"),
+        Err(err) => warn!(error = %err, "no transaction result"),
+    }
+}
+
+pub async fn deserialize_csv(tx: Sender<TransactionMessage>, shed: UnboundedSender<TransactionMessage>, reader: impl AsyncRead + Unpin + Send + Sync, format: InputFormat, ordering: EventOrdering, admission: Admission) -> Result<(), IngestionError>
 {
 
     let mut deserializer = AsyncReaderBuilder::new()
         .trim(Trim::All)
+        .delimiter(format.delimiter)
         .create_deserializer(reader);
     let mut records = deserializer.deserialize::<Transaction>();
+    let mut sequence = 0;
+    let mut buffer = BinaryHeap::new();
+    let mut watermark = None;
+    let mut stamped = None;
+    let mut pending = Pending::new();
 
-    while let Some(record) = records.next().await{
+    loop {
+        let record = tokio::select! {
+            biased;
+            Some(()) = pending.next() => continue,
+            record = records.next() => match record {
+                Some(record) => record,
+                None => break,
+            },
+        };
+        sequence += 1;
         match record {
-            Ok(record) => {
-                let (otx, orx) = oneshot::channel::<Result<(), TransactionError>>();
-
-                let message = TransactionMessage {
-                    transaction: record,
-                    sender: otx,
-                };
-
-                if tx.send(message).await.is_err() {
-                    panic!("Internal server error, cannot send deserialized record to transaction manager!");
+            Ok(mut record) => {
+                record.sequence = sequence;
+                if let Some(amount) = record.amount {
+                    match format.amount(amount) {
+                        Some(amount) => record.amount = Some(amount),
+                        None => {
+                            row_span(&record).in_scope(|| warn!(error = %format!("amount {} cannot keep {} decimals", amount, format.precision), "unable to parse record"));
+                            continue
+                        }
+                    }
+                }
+                // The first row decides whether the input is stamped, row numbers and epoch millis do not compare.
+                if *stamped.get_or_insert(record.timestamp.is_some()) != record.timestamp.is_some() {
+                    let span = row_span(&record);
+                    if submit(&tx, &shed, record, Some(TransactionError::MixedTimestamps), admission, &mut pending).instrument(span).await.is_err() {
+                        break
+                    }
+                    continue
                 }
-                if let Err(err) = orx.await.unwrap() {
-                    eprintln!("Transaction error {:?}",err)
+                if watermark.is_some_and(|watermark| record.time() < watermark) {
+                    let span = row_span(&record);
+                    let rejection = match ordering.late_policy {
+                        LatePolicy::Accept => None,
+                        LatePolicy::Drop => {
+                            span.in_scope(|| warn!("late transaction dropped"));
+                            continue
+                        },
+                        LatePolicy::Reject => Some(TransactionError::LateTransaction),
+                    };
+                    if submit(&tx, &shed, record, rejection, admission, &mut pending).instrument(span).await.is_err() {
+                        break
+                    }
+                    continue
+                }
+
+                buffer.push(Reverse(Buffered(record)));
+                if buffer.len() > ordering.buffer {
+                    if let Some(Reverse(Buffered(next))) = buffer.pop() {
+                        watermark = Some(next.time());
+                        let span = row_span(&next);
+                        if submit(&tx, &shed, next, None, admission, &mut pending).instrument(span).await.is_err() {
+                            break
+                        }
+                    }
                 }
             },
-            Err(err) => eprintln!("Unable to parse record: {:?}", err)
+            Err(err) => info_span!("row", row = sequence).in_scope(|| warn!(error = %err, "unable to parse record")),
         }
     }
+
+    while pending.next().await.is_some() {}
+    if tx.is_closed() {
+        warn!(row = sequence, buffered = buffer.len(), "transaction channel closed, stopped reading input");
+        return Err(IngestionError::ChannelClosed)
+    }
+    while let Some(Reverse(Buffered(next))) = buffer.pop() {
+        let span = row_span(&next);
+        submit(&tx, &shed, next, None, admission, &mut pending).instrument(span).await?;
+    }
+    while pending.next().await.is_some() {}
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use tokio::fs::File;
+    use tokio::sync::mpsc::unbounded_channel;
     use crate::csv_parser::*;
+    use crate::ids::{client_id, tx_id};
+    use proptest::prelude::*;
 
     #[tokio::test]
     async fn test_simple_csv_parse() {
@@ -124,40 +562,320 @@ mod tests {
         let file = File::open("test/parse.csv").await.unwrap();
 
         tokio::spawn(async move {
-            deserialize_csv(tx,file).await;
+            deserialize_csv(tx,unbounded_channel().0,file,InputFormat::default(),EventOrdering::default(),Admission::default()).await.unwrap();
         });
 
         let mut transactions = Vec::new();
         while let Some(message) = rx.recv().await {
             transactions.push(message.transaction);
-            message.sender.send(Ok(())).unwrap();
+            message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
         }
 
         assert!(matches!(transactions[0].trans_type, TransactionType::Dispute));
-        assert_eq!(transactions[0].client, 1);
-        assert_eq!(transactions[0].tx, 5);
+        assert_eq!(transactions[0].sequence, 1);
+        assert_eq!(transactions[0].time(), 1);
+        assert_eq!(transactions[0].client, client_id(1));
+        assert_eq!(transactions[0].tx, tx_id(5));
         assert_eq!(transactions[0].amount, None);
 
         assert!(matches!(transactions[1].trans_type, TransactionType::Deposit));
-        assert_eq!(transactions[1].client, 2);
-        assert_eq!(transactions[1].tx, 4);
+        assert_eq!(transactions[1].client, client_id(2));
+        assert_eq!(transactions[1].tx, tx_id(4));
         assert_eq!(transactions[1].amount.unwrap(), 1.0);
 
         assert!(matches!(transactions[2].trans_type, TransactionType::WithDrawal));
-        assert_eq!(transactions[2].client, 3);
-        assert_eq!(transactions[2].tx, 3);
+        assert_eq!(transactions[2].client, client_id(3));
+        assert_eq!(transactions[2].tx, tx_id(3));
         assert_eq!(transactions[2].amount.unwrap(), 3.0);
 
         assert!(matches!(transactions[3].trans_type, TransactionType::Resolve));
-        assert_eq!(transactions[3].client, 4);
-        assert_eq!(transactions[3].tx, 2);
+        assert_eq!(transactions[3].client, client_id(4));
+        assert_eq!(transactions[3].tx, tx_id(2));
         assert_eq!(transactions[3].amount, None);
 
         assert!(matches!(transactions[4].trans_type, TransactionType::ChargeBack));
-        assert_eq!(transactions[4].client, 5);
-        assert_eq!(transactions[4].tx, 1);
+        assert_eq!(transactions[4].client, client_id(5));
+        assert_eq!(transactions[4].tx, tx_id(1));
         assert_eq!(transactions[4].amount, None);
     }
-}
 
+    #[tokio::test]
+    async fn test_transfer_csv_parse() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+
+        let file = File::open("test/parse_transfer.csv").await.unwrap();
+
+        tokio::spawn(async move {
+            deserialize_csv(tx,unbounded_channel().0,file,InputFormat::default(),EventOrdering::default(),Admission::default()).await.unwrap();
+        });
+
+        let mut transactions = Vec::new();
+        while let Some(message) = rx.recv().await {
+            transactions.push(message.transaction);
+            message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
+        }
+
+        assert!(matches!(transactions[0].trans_type, TransactionType::Deposit));
+        assert_eq!(transactions[0].destination, None);
+
+        assert!(matches!(transactions[1].trans_type, TransactionType::Transfer));
+        assert_eq!(transactions[1].client, client_id(1));
+        assert_eq!(transactions[1].tx, tx_id(2));
+        assert_eq!(transactions[1].amount.unwrap(), 0.5);
+        assert_eq!(transactions[1].destination, Some(client_id(2)));
+        assert_eq!(transactions[1].timestamp, Some(1628000000000));
+        assert_eq!(transactions[1].time(), 1628000000000);
+    }
+
+    async fn collect(path: &str, ordering: EventOrdering) -> Vec<Transaction> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+
+        let file = File::open(path).await.unwrap();
+
+        tokio::spawn(async move {
+            deserialize_csv(tx,unbounded_channel().0,file,InputFormat::default(),ordering,Admission::default()).await.unwrap();
+        });
+
+        let mut transactions = Vec::new();
+        while let Some(message) = rx.recv().await {
+            match message.rejection {
+                Some(err) => message.sender.send(Err(err)).unwrap(),
+                None => {
+                    transactions.push(message.transaction);
+                    message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
+                },
+            }
+        }
+        transactions
+    }
+
+    async fn rejections(input: &'static str, ordering: EventOrdering) -> Vec<(TxId, TransactionError)> {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+
+        tokio::spawn(async move {
+            deserialize_csv(tx,unbounded_channel().0,input.as_bytes(),InputFormat::default(),ordering,Admission::default()).await.unwrap();
+        });
+
+        let mut rejections = Vec::new();
+        while let Some(message) = rx.recv().await {
+            if let Some(err) = message.rejection {
+                rejections.push((message.transaction.tx, err));
+            }
+            let _ = message.sender.send(Ok(TransactionStatus::Applied));
+        }
+        rejections
+    }
+
+    #[tokio::test]
+    async fn test_closed_channel_stops_reading() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+
+        let file = File::open("test/parse.csv").await.unwrap();
+
+        let parser = tokio::spawn(async move {
+            deserialize_csv(tx,unbounded_channel().0,file,InputFormat::default(),EventOrdering::default(),Admission::default()).await
+        });
+
+        let message = rx.recv().await.unwrap();
+        rx.close();
+        message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
+
+        let mut drained = 0;
+        while let Some(message) = rx.recv().await {
+            drained += 1;
+            message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
+        }
+        assert!(drained <= 1);
+        assert_eq!(parser.await.unwrap(), Err(IngestionError::ChannelClosed));
+    }
+
+    // A consumer slower than the reader: the rows it has not picked up yet fill the queue, the reader does not wait for their results.
+    // Shed rows are answered as they come, with the rejection they carry.
+    async fn slow_consumer(admission: Admission) -> (usize, usize, Vec<u64>) {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(2);
+        let (shed_tx, mut shed_rx) = unbounded_channel();
+
+        let file = File::open("test/parse.csv").await.unwrap();
+        let parser = tokio::spawn(async move {
+            deserialize_csv(tx,shed_tx,file,InputFormat::default(),EventOrdering::default(),admission).await
+        });
 
+        let mut queued = 0;
+        let mut received = 0;
+        let mut shed = Vec::new();
+        loop {
+            let message = tokio::select! {
+                biased;
+                Some(message) = shed_rx.recv() => {
+                    assert!(matches!(message.rejection, Some(TransactionError::QueueFull)));
+                    shed.push(message.transaction.sequence);
+                    message.sender.send(Err(TransactionError::QueueFull)).unwrap();
+                    continue
+                },
+                _ = tokio::time::sleep(Duration::from_millis(50)) => {
+                    queued = queued.max(rx.len());
+                    rx.recv().await
+                },
+            };
+            match message {
+                Some(message) => {
+                    received += 1;
+                    message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
+                },
+                None => break,
+            }
+        }
+        assert_eq!(parser.await.unwrap(), Ok(()));
+        (queued, received, shed)
+    }
+
+    #[tokio::test]
+    async fn test_queue_fills_behind_slow_consumer() {
+        let (queued, received, shed) = slow_consumer(Admission::default()).await;
+        assert_eq!(queued, 2);
+        assert_eq!(received, 5);
+        assert!(shed.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shed_when_queue_is_full() {
+        let (queued, received, shed) = slow_consumer(Admission { policy: AdmissionPolicy::Shed, result_timeout: None }).await;
+        assert_eq!(queued, 2);
+        assert!(received < 5);
+        assert_eq!(received + shed.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_result_timeout_and_dropped_result() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+
+        let file = File::open("test/parse.csv").await.unwrap();
+        let admission = Admission { policy: AdmissionPolicy::Block, result_timeout: Some(Duration::from_millis(10)) };
+        let parser = tokio::spawn(async move {
+            deserialize_csv(tx,unbounded_channel().0,file,InputFormat::default(),EventOrdering::default(),admission).await
+        });
+
+        // The first result never comes and the others are dropped, every row is still read.
+        let mut pending = Vec::new();
+        let mut received = 0;
+        while let Some(message) = rx.recv().await {
+            received += 1;
+            if received == 1 {
+                pending.push(message.sender);
+            }
+        }
+        assert_eq!(received, 5);
+        assert_eq!(parser.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_timestamp_csv_parse() {
+        let transactions = collect("test/parse_timestamp.csv", EventOrdering::default()).await;
+
+        let order: Vec<TxId> = transactions.iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(order, [1, 2, 3, 4].map(tx_id));
+        assert_eq!(transactions[0].timestamp, Some(1627992002000));
+        assert_eq!(transactions[1].timestamp, Some(1627992001000));
+        assert_eq!(transactions[2].timestamp, Some(1627992003000));
+        assert_eq!(transactions[3].timestamp, Some(1627992000500));
+    }
+
+    #[tokio::test]
+    async fn test_reorder_buffer_accept_late() {
+        let transactions = collect("test/parse_timestamp.csv", EventOrdering { buffer: 1, late_policy: LatePolicy::Accept }).await;
+
+        let order: Vec<TxId> = transactions.iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(order, [2, 1, 4, 3].map(tx_id));
+    }
+
+    #[tokio::test]
+    async fn test_reorder_buffer_drop_late() {
+        let transactions = collect("test/parse_timestamp.csv", EventOrdering { buffer: 1, late_policy: LatePolicy::Drop }).await;
+
+        let order: Vec<TxId> = transactions.iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(order, [2, 1, 3].map(tx_id));
+    }
+
+    #[tokio::test]
+    async fn test_reorder_buffer_large_enough() {
+        let transactions = collect("test/parse_timestamp.csv", EventOrdering { buffer: 3, late_policy: LatePolicy::Reject }).await;
+
+        let order: Vec<TxId> = transactions.iter().map(|transaction| transaction.tx).collect();
+        assert_eq!(order, [4, 2, 1, 3].map(tx_id));
+    }
+
+    #[tokio::test]
+    async fn test_reorder_buffer_reject_late() {
+        let input = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0, 2000\ndeposit, 1, 2, 1.0, 3000\ndeposit, 1, 3, 1.0, 1000\n";
+        let rejected = rejections(input, EventOrdering { buffer: 1, late_policy: LatePolicy::Reject }).await;
+
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, tx_id(3));
+        assert!(matches!(rejected[0].1, TransactionError::LateTransaction));
+    }
+
+    #[tokio::test]
+    async fn test_mixed_timestamps_rejected() {
+        let input = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0, 1627992001000\ndeposit, 1, 2, 1.0,\ndeposit, 1, 3, 1.0, 1627992002000\n";
+        let rejected = rejections(input, EventOrdering::default()).await;
+
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, tx_id(2));
+        assert!(matches!(rejected[0].1, TransactionError::MixedTimestamps));
+
+        let input = "type, client, tx, amount, timestamp\ndeposit, 1, 1, 1.0,\ndeposit, 1, 2, 1.0, 1627992001000\n";
+        let rejected = rejections(input, EventOrdering::default()).await;
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, tx_id(2));
+    }
+
+    #[tokio::test]
+    async fn test_input_format() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel(10);
+        let input = "type;client;tx;amount\ndeposit;1;1;1.23456\nwithdrawal;1;2;0.005\ndeposit;1;3;1e308\n";
+        let format = InputFormat { delimiter: b';', precision: 2, rounding: Rounding::Round };
+
+        tokio::spawn(async move {
+            deserialize_csv(tx,unbounded_channel().0,input.as_bytes(),format,EventOrdering::default(),Admission::default()).await.unwrap();
+        });
+
+        let mut amounts = Vec::new();
+        while let Some(message) = rx.recv().await {
+            amounts.push(message.transaction.amount.unwrap());
+            message.sender.send(Ok(TransactionStatus::Applied)).unwrap();
+        }
+        assert_eq!(amounts, vec![1.23, 0.01]);
+
+        assert_eq!(InputFormat::default().amount(0.00019), Some(0.0001));
+        assert_eq!(InputFormat { precision: 0, ..InputFormat::default() }.amount(2.7), Some(2.0));
+        assert_eq!(InputFormat::default().amount(1e308), None);
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1627992001000"), Ok(1627992001000));
+        assert_eq!(parse_timestamp("2021-08-03T12:00:01Z"), Ok(1627992001000));
+        assert!(parse_timestamp("1969-12-31T23:59:59Z").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+        assert_eq!(format_timestamp(1627992001000), "2021-08-03T12:00:01.000Z");
+    }
+
+    proptest! {
+        // Same check as the fuzz target, on bytes that look like a header followed by rows.
+        #[test]
+        fn prop_arbitrary_bytes_do_not_panic(body in proptest::collection::vec(any::<u8>(), 0..512), buffer in 0usize..3) {
+            let mut input = b"type,client,tx,amount,destination,timestamp\n".to_vec();
+            input.extend(body);
+            let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+            let result = runtime.block_on(async move {
+                let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+                let parser = tokio::spawn(deserialize_csv(tx, unbounded_channel().0, std::io::Cursor::new(input), InputFormat::default(), EventOrdering { buffer, late_policy: LatePolicy::Reject }, Admission::default()));
+                while let Some(message) = rx.recv().await {
+                    let _ = message.sender.send(Ok(TransactionStatus::Applied));
+                }
+                parser.await.unwrap()
+            });
+            prop_assert_eq!(result, Ok(()));
+        }
+    }
+}
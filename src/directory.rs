@@ -0,0 +1,154 @@
+use crate::csv_parser::{TransactionError, TransactionType, deserialize_records};
+use crate::ids::ClientId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tokio::io::AsyncRead;
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    Suspended,
+    Closed,
+}
+
+impl AccountStatus {
+    // A suspended account only takes operator reversals, a closed one takes nothing.
+    pub fn check(&self, trans_type: TransactionType) -> Result<(), TransactionError> {
+        match self {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Suspended if trans_type == TransactionType::Reversal => Ok(()),
+            AccountStatus::Suspended => Err(TransactionError::AccountSuspended),
+            AccountStatus::Closed => Err(TransactionError::AccountClosed),
+        }
+    }
+}
+
+impl Display for AccountStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountStatus::Active => write!(f, "active"),
+            AccountStatus::Suspended => write!(f, "suspended"),
+            AccountStatus::Closed => write!(f, "closed"),
+        }
+    }
+}
+
+// One row of the accounts master file. An empty status means active, `max_withdrawal` caps every outgoing
+// amount of the client on top of the limits of its tier.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientRecord {
+    pub client: ClientId,
+    pub name: Option<String>,
+    pub tier: Option<String>,
+    pub status: Option<AccountStatus>,
+    pub currency: Option<String>,
+    pub max_withdrawal: Option<f64>,
+}
+
+impl ClientRecord {
+    pub fn status(&self) -> AccountStatus {
+        self.status.unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum UnknownClientPolicy {
+    #[default]
+    Create,
+    Reject,
+}
+
+impl FromStr for UnknownClientPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "create" => Ok(UnknownClientPolicy::Create),
+            "reject" => Ok(UnknownClientPolicy::Reject),
+            _ => Err(format!("Unknown client policy {}, expected create or reject", policy)),
+        }
+    }
+}
+
+impl Display for UnknownClientPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnknownClientPolicy::Create => write!(f, "create"),
+            UnknownClientPolicy::Reject => write!(f, "reject"),
+        }
+    }
+}
+
+// Clients known before processing starts, with what to do about the ones that are not.
+#[derive(Debug, Default)]
+pub struct Directory {
+    clients: HashMap<ClientId, ClientRecord>,
+    unknown_clients: UnknownClientPolicy,
+}
+
+impl Directory {
+    pub fn new(records: Vec<ClientRecord>, unknown_clients: UnknownClientPolicy) -> Directory {
+        Directory {
+            clients: records.into_iter().map(|record| (record.client, record)).collect(),
+            unknown_clients,
+        }
+    }
+
+    pub async fn load(reader: impl AsyncRead + Unpin + Send + Sync, unknown_clients: UnknownClientPolicy) -> Result<Directory, csv_async::Error> {
+        let records = deserialize_records::<ClientRecord>(reader).await?;
+        Ok(Directory::new(records, unknown_clients))
+    }
+
+    pub fn client(&self, client: ClientId) -> Option<&ClientRecord> {
+        self.clients.get(&client)
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &ClientRecord> {
+        self.clients.values()
+    }
+
+    pub fn unknown_clients(&self) -> UnknownClientPolicy {
+        self.unknown_clients
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::File;
+    use crate::directory::*;
+    use crate::ids::client_id;
+
+    #[tokio::test]
+    async fn test_directory_load() {
+        let file = File::open("test/accounts.csv").await.unwrap();
+        let directory = Directory::load(file, UnknownClientPolicy::Reject).await.unwrap();
+
+        let alice = directory.client(client_id(1)).unwrap();
+        assert_eq!(alice.name.as_deref(), Some("Alice"));
+        assert_eq!(alice.tier.as_deref(), Some("gold"));
+        assert_eq!(alice.status(), AccountStatus::Active);
+        assert_eq!(alice.currency.as_deref(), Some("EUR"));
+        assert_eq!(alice.max_withdrawal, None);
+
+        assert_eq!(directory.client(client_id(2)).unwrap().status(), AccountStatus::Suspended);
+        assert_eq!(directory.client(client_id(3)).unwrap().status(), AccountStatus::Closed);
+        assert_eq!(directory.client(client_id(4)).unwrap().max_withdrawal, Some(50.0));
+        assert_eq!(directory.client(client_id(5)), None);
+        assert_eq!(directory.records().count(), 4);
+        assert_eq!(directory.unknown_clients(), UnknownClientPolicy::Reject);
+    }
+
+    #[test]
+    fn test_account_status() {
+        assert!(AccountStatus::Active.check(TransactionType::WithDrawal).is_ok());
+        assert!(matches!(AccountStatus::Suspended.check(TransactionType::Deposit), Err(TransactionError::AccountSuspended)));
+        assert!(AccountStatus::Suspended.check(TransactionType::Reversal).is_ok());
+        assert!(matches!(AccountStatus::Closed.check(TransactionType::Reversal), Err(TransactionError::AccountClosed)));
+        assert_eq!("reject".parse::<UnknownClientPolicy>(), Ok(UnknownClientPolicy::Reject));
+        assert!("ignore".parse::<UnknownClientPolicy>().is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+// Failures of the engine itself, as opposed to a TransactionError rejecting a single transaction.
+#[derive(Debug)]
+pub enum EngineError {
+    Open { file: String, source: io::Error },
+    Parse { file: String, reason: String },
+    Bind { addr: String, source: io::Error },
+    Write { file: String, reason: String },
+    Interrupted,
+}
+
+impl EngineError {
+    pub fn open(file: &str, source: io::Error) -> EngineError {
+        EngineError::Open { file: file.to_owned(), source }
+    }
+
+    pub fn parse(file: &str, reason: impl Display) -> EngineError {
+        EngineError::Parse { file: file.to_owned(), reason: reason.to_string() }
+    }
+
+    pub fn write(file: &str, reason: impl Display) -> EngineError {
+        EngineError::Write { file: file.to_owned(), reason: reason.to_string() }
+    }
+}
+
+impl Display for EngineError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Open { file, source } => {write!(f, "Cannot open {}: {}", file, source)}
+            EngineError::Parse { file, reason } => {write!(f, "Cannot parse {}: {}", file, reason)}
+            EngineError::Bind { addr, source } => {write!(f, "Cannot bind {}: {}", addr, source)}
+            EngineError::Write { file, reason } => {write!(f, "Cannot write {}: {}", file, reason)}
+            EngineError::Interrupted => {write!(f, "Interrupted by a second shutdown signal, no output written")}
+        }
+    }
+}
+
+impl Error for EngineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EngineError::Open { source, .. } | EngineError::Bind { source, .. } => Some(source),
+            EngineError::Parse { .. } | EngineError::Write { .. } | EngineError::Interrupted => None,
+        }
+    }
+}
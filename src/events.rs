@@ -0,0 +1,181 @@
+use crate::csv_parser::{TransactionType, deserialize_records};
+use crate::ids::{ClientId, TxId};
+use crate::directory::AccountStatus;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use csv_async::AsyncWriterBuilder;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    None,
+    Open,
+    Resolved,
+    ChargedBack,
+}
+
+// One applied transaction as seen by one client, with the balances right after it. An account opened without one,
+// from the master file or by a rejected transaction, starts with an `open` event, without tx for the master file.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AccountEvent {
+    pub event: u64,
+    pub client: ClientId,
+    pub tx: Option<TxId>,
+    #[serde(rename = "type")]
+    pub trans_type: TransactionType,
+    pub timestamp: Option<u64>,
+    pub amount: Option<f64>,
+    pub fee: f64,
+    pub dispute: DisputeState,
+    pub reversed: bool,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+    pub overdrawn: f64,
+    #[serde(default)]
+    pub status: AccountStatus,
+}
+
+// Same columns as the account output so a rebuilt state can be diffed against it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AccountState {
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+    pub overdrawn: f64,
+    #[serde(default)]
+    pub status: AccountStatus,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EventFormat {
+    Csv,
+    Jsonl,
+}
+
+impl FromStr for EventFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "csv" => Ok(EventFormat::Csv),
+            "jsonl" => Ok(EventFormat::Jsonl),
+            _ => Err(format!("Unknown event format {}, expected csv or jsonl", format)),
+        }
+    }
+}
+
+impl Display for EventFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventFormat::Csv => {write!(f, "csv")}
+            EventFormat::Jsonl => {write!(f, "jsonl")}
+        }
+    }
+}
+
+// Events grouped per client, each client's stream kept in processing order.
+pub fn per_client(events: &[AccountEvent]) -> Vec<&AccountEvent> {
+    let mut events: Vec<&AccountEvent> = events.iter().collect();
+    events.sort_by_key(|event| (event.client, event.event));
+    events
+}
+
+// Every event carries the balances after it, so the last one of each client is its final state.
+pub fn rebuild<'a>(events: impl IntoIterator<Item = &'a AccountEvent>) -> Vec<AccountState> {
+    let mut states = BTreeMap::new();
+    for event in events {
+        let latest = states.entry(event.client).or_insert(event);
+        if event.event >= latest.event {
+            *latest = event;
+        }
+    }
+    states.values().map(|event| AccountState {
+        client: event.client,
+        available: event.available,
+        held: event.held,
+        total: event.total,
+        locked: event.locked,
+        overdrawn: event.overdrawn,
+        status: event.status,
+    }).collect()
+}
+
+pub async fn load(reader: impl AsyncRead + Unpin + Send + Sync, format: EventFormat) -> Result<Vec<AccountEvent>, String> {
+    match format {
+        EventFormat::Csv => deserialize_records(reader).await.map_err(|err| format!("{:?}", err)),
+        EventFormat::Jsonl => {
+            let mut lines = BufReader::new(reader).lines();
+            let mut events = Vec::new();
+            while let Some(line) = lines.next_line().await.map_err(|err| format!("{:?}", err))? {
+                if !line.trim().is_empty() {
+                    events.push(serde_json::from_str(&line).map_err(|err| format!("{}", err))?);
+                }
+            }
+            Ok(events)
+        },
+    }
+}
+
+pub async fn write<'a>(writer: impl AsyncWrite + Unpin + Send, format: EventFormat, events: impl IntoIterator<Item = &'a AccountEvent>) -> Result<(), String> {
+    match format {
+        EventFormat::Csv => {
+            let mut serializer = AsyncWriterBuilder::new()
+                .delimiter(b',')
+                .create_serializer(writer);
+            for event in events {
+                serializer.serialize(event).await.map_err(|err| format!("{:?}", err))?;
+            }
+            serializer.flush().await.map_err(|err| format!("{:?}", err))
+        },
+        EventFormat::Jsonl => {
+            let mut writer = writer;
+            for event in events {
+                let mut line = serde_json::to_string(event).map_err(|err| format!("{}", err))?;
+                line.push('\n');
+                writer.write_all(line.as_bytes()).await.map_err(|err| format!("{:?}", err))?;
+            }
+            writer.flush().await.map_err(|err| format!("{:?}", err))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::File;
+    use crate::events::*;
+    use crate::ids::client_id;
+
+    #[tokio::test]
+    async fn test_load_csv_and_rebuild() {
+        let file = File::open("test/events.csv").await.unwrap();
+        let events = load(file, EventFormat::Csv).await.unwrap();
+
+        assert_eq!(events.len(), 5);
+        assert_eq!(events[2].dispute, DisputeState::Open);
+        assert_eq!(rebuild(&events), vec![
+            AccountState { client: client_id(1), available: 0.0, held: 0.0, total: 0.0, locked: true, overdrawn: 0.0, status: AccountStatus::Active },
+            AccountState { client: client_id(2), available: 5.0, held: 0.0, total: 5.0, locked: false, overdrawn: 0.0, status: AccountStatus::Active },
+        ]);
+        assert_eq!(per_client(&events).iter().map(|event| event.event).collect::<Vec<u64>>(), vec![1, 3, 4, 2, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_jsonl_round_trip() {
+        let file = File::open("test/events.csv").await.unwrap();
+        let events = load(file, EventFormat::Csv).await.unwrap();
+
+        let mut output = Vec::new();
+        write(&mut output, EventFormat::Jsonl, &events).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output).lines().count(), 5);
+
+        let reloaded = load(output.as_slice(), EventFormat::Jsonl).await.unwrap();
+        assert_eq!(reloaded, events);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::csv_parser::{TransactionType, deserialize_records};
+use crate::ids::{ClientId, RESERVED_CLIENT};
+use serde::{Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use tokio::io::AsyncRead;
+use std::collections::HashMap;
+
+pub const DEFAULT_HOUSE_ACCOUNT: ClientId = RESERVED_CLIENT;
+
+#[derive(Deserialize, Debug, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeKind {
+    Flat,
+    Percentage,
+}
+
+fn non_negative_deserialize<'de, D>(de: D) -> Result<Option<f64>, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    let value: Option<f64> = Option::deserialize(de)?;
+    match value {
+        Some(value) if !(value >= 0.0 && value.is_finite()) => Err(DeError::custom(format!("fee {} is not a non-negative number", value))),
+        _ => Ok(value),
+    }
+}
+
+fn value_deserialize<'de, D>(de: D) -> Result<f64, D::Error>
+    where
+        D: Deserializer<'de>,
+{
+    non_negative_deserialize(de)?.ok_or_else(|| DeError::custom("fee value is missing"))
+}
+
+// One row of the fee schedule file. Several rows for the same transaction type form tiers,
+// the row with the highest `from` not above the transaction amount is used. Values and caps are never negative.
+#[derive(Deserialize, Debug, Clone)]
+pub struct FeeRule {
+    #[serde(alias = "type")]
+    pub trans_type: TransactionType,
+    pub kind: FeeKind,
+    #[serde(deserialize_with = "value_deserialize")]
+    pub value: f64,
+    pub from: Option<f64>,
+    #[serde(default, deserialize_with = "non_negative_deserialize")]
+    pub cap: Option<f64>,
+}
+
+impl FeeRule {
+    fn lower_bound(&self) -> f64 {
+        self.from.unwrap_or(0.0)
+    }
+
+    fn fee(&self, amount: f64) -> f64 {
+        let fee = match self.kind {
+            FeeKind::Flat => self.value,
+            FeeKind::Percentage => amount * self.value / 100.0,
+        };
+        let fee = match self.cap {
+            Some(cap) => fee.min(cap),
+            None => fee,
+        };
+        (fee * 10000.0).trunc() / 10000.0
+    }
+}
+
+// Only a loaded schedule has a house account; without one every client id belongs to a client.
+#[derive(Debug)]
+pub struct FeeSchedule {
+    pub house_account: Option<ClientId>,
+    rules: HashMap<TransactionType, Vec<FeeRule>>,
+}
+
+impl FeeSchedule {
+    pub fn new(house_account: ClientId, rules: Vec<FeeRule>) -> FeeSchedule {
+        let mut by_type: HashMap<TransactionType, Vec<FeeRule>> = HashMap::new();
+        for rule in rules {
+            by_type.entry(rule.trans_type).or_default().push(rule);
+        }
+        for tiers in by_type.values_mut() {
+            tiers.sort_by(|a, b| a.lower_bound().total_cmp(&b.lower_bound()));
+        }
+        FeeSchedule { house_account: Some(house_account), rules: by_type }
+    }
+
+    pub fn empty() -> FeeSchedule {
+        FeeSchedule { house_account: None, rules: HashMap::new() }
+    }
+
+    pub fn is_house(&self, client: ClientId) -> bool {
+        self.house_account == Some(client)
+    }
+
+    pub async fn load(house_account: ClientId, reader: impl AsyncRead + Unpin + Send + Sync) -> Result<FeeSchedule, csv_async::Error> {
+        Ok(FeeSchedule::new(house_account, deserialize_records::<FeeRule>(reader).await?))
+    }
+
+    pub fn fee(&self, trans_type: TransactionType, amount: f64) -> f64 {
+        match self.rules.get(&trans_type) {
+            Some(tiers) => tiers.iter()
+                .rev()
+                .find(|rule| rule.lower_bound() <= amount)
+                .map_or(0.0, |rule| rule.fee(amount)),
+            None => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::File;
+    use crate::fee::*;
+    use crate::ids::client_id;
+
+    fn rule(trans_type: TransactionType, kind: FeeKind, value: f64, from: Option<f64>, cap: Option<f64>) -> FeeRule {
+        FeeRule { trans_type, kind, value, from, cap }
+    }
+
+    #[test]
+    fn test_flat_fee() {
+        let schedule = FeeSchedule::new(DEFAULT_HOUSE_ACCOUNT, vec![rule(TransactionType::WithDrawal, FeeKind::Flat, 0.5, None, None)]);
+
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 10.0), 0.5);
+        assert_eq!(schedule.fee(TransactionType::Deposit, 10.0), 0.0);
+    }
+
+    #[test]
+    fn test_percentage_fee_with_cap() {
+        let schedule = FeeSchedule::new(DEFAULT_HOUSE_ACCOUNT, vec![rule(TransactionType::WithDrawal, FeeKind::Percentage, 1.0, None, Some(2.0))]);
+
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 100.0), 1.0);
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 1000.0), 2.0);
+    }
+
+    #[test]
+    fn test_tiered_fee() {
+        let schedule = FeeSchedule::new(DEFAULT_HOUSE_ACCOUNT, vec![
+            rule(TransactionType::WithDrawal, FeeKind::Percentage, 1.0, Some(100.0), None),
+            rule(TransactionType::WithDrawal, FeeKind::Flat, 0.5, None, None),
+        ]);
+
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 10.0), 0.5);
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 200.0), 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_fee_schedule_load() {
+        let file = File::open("test/fees.csv").await.unwrap();
+        let schedule = FeeSchedule::load(client_id(7), file).await.unwrap();
+
+        assert_eq!(schedule.house_account, Some(client_id(7)));
+        assert!(schedule.is_house(client_id(7)));
+        assert!(!FeeSchedule::empty().is_house(DEFAULT_HOUSE_ACCOUNT));
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 50.0), 0.5);
+        assert_eq!(schedule.fee(TransactionType::WithDrawal, 5000.0), 10.0);
+        assert_eq!(schedule.fee(TransactionType::ChargeBack, 1.0), 15.0);
+    }
+
+    #[tokio::test]
+    async fn test_fee_schedule_load_rejects_negative_fees() {
+        let negative_value = "type, kind, value, from, cap\nwithdrawal, flat, -0.5, ,\n";
+        assert!(FeeSchedule::load(client_id(7), negative_value.as_bytes()).await.is_err());
+
+        let negative_cap = "type, kind, value, from, cap\nwithdrawal, percentage, 1.0, , -2\n";
+        assert!(FeeSchedule::load(client_id(7), negative_cap.as_bytes()).await.is_err());
+
+        let free = "type, kind, value, from, cap\nwithdrawal, flat, 0, ,\n";
+        assert_eq!(FeeSchedule::load(client_id(7), free.as_bytes()).await.unwrap().fee(TransactionType::WithDrawal, 1.0), 0.0);
+    }
+}
@@ -0,0 +1,236 @@
+use crate::csv_parser::{Transaction, TransactionType};
+use crate::ids;
+use csv_async::AsyncWriterBuilder;
+use tokio::io::AsyncWrite;
+
+pub const DEFAULT_CLIENTS: u64 = 1000;
+pub const DEFAULT_TRANSACTIONS: u64 = 1_000_000;
+pub const DEFAULT_DISPUTE_RATIO: f64 = 0.01;
+pub const DEFAULT_SEED: u64 = 42;
+
+const START_TIME: u64 = 1627992000000;
+// Only recent deposits get disputed, which keeps the generator memory bounded for any row count.
+const RECENT_DEPOSITS: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratorOptions {
+    pub clients: u64,
+    pub transactions: u64,
+    pub dispute_ratio: f64,
+    pub seed: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions { clients: DEFAULT_CLIENTS, transactions: DEFAULT_TRANSACTIONS, dispute_ratio: DEFAULT_DISPUTE_RATIO, seed: DEFAULT_SEED }
+    }
+}
+
+// Deterministic stream of transactions: the same options always give the same rows.
+// Clients and transactions are numbered from 1 up to what the id type holds, the house account is never generated.
+// Deposits, withdrawals and transfers make up the bulk, a dispute_ratio share of the rows opens a dispute on a recent
+// deposit or settles an open one, about one settlement in twenty being a chargeback so that most accounts stay unlocked.
+pub struct Generator {
+    options: GeneratorOptions,
+    state: u64,
+    row: u64,
+    time: u64,
+    deposits: Vec<(u64, u64)>,
+    disputes: Vec<(u64, u64)>,
+}
+
+impl Generator {
+    pub fn new(options: GeneratorOptions) -> Generator {
+        let options = GeneratorOptions { clients: options.clients.clamp(1, ids::MAX_CLIENT_NUMBER), ..options };
+        Generator { state: options.seed.max(1), options, row: 0, time: START_TIME, deposits: Vec::new(), disputes: Vec::new() }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64) < probability * (1u64 << 53) as f64
+    }
+
+    fn client(&mut self) -> u64 {
+        self.below(self.options.clients) + 1
+    }
+
+    // Any client but the source, each as likely.
+    fn destination(&mut self, source: u64) -> u64 {
+        let destination = self.below(self.options.clients - 1) + 1;
+        if destination >= source { destination + 1 } else { destination }
+    }
+
+    fn amount(&mut self, max_units: u64) -> f64 {
+        (self.below(max_units) + 1) as f64 / 10000.0
+    }
+
+    fn dispute_row(&mut self) -> Option<(TransactionType, u64, u64)> {
+        if !self.disputes.is_empty() && (self.deposits.is_empty() || self.chance(0.5)) {
+            let index = self.below(self.disputes.len() as u64) as usize;
+            let (client, tx) = self.disputes.swap_remove(index);
+            let trans_type = if self.chance(0.05) { TransactionType::ChargeBack } else { TransactionType::Resolve };
+            return Some((trans_type, client, tx))
+        }
+        if self.deposits.is_empty() {
+            return None
+        }
+        let index = self.below(self.deposits.len() as u64) as usize;
+        let (client, tx) = self.deposits.swap_remove(index);
+        self.disputes.push((client, tx));
+        Some((TransactionType::Dispute, client, tx))
+    }
+}
+
+impl Iterator for Generator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Transaction> {
+        if self.row >= self.options.transactions {
+            return None
+        }
+        self.row += 1;
+        self.time += self.below(1000);
+        let tx = self.row;
+
+        let mut transaction = Transaction {
+            trans_type: TransactionType::Deposit,
+            client: ids::client_id(0),
+            tx: ids::tx_id(tx),
+            amount: None,
+            destination: None,
+            timestamp: Some(self.time),
+            sequence: 0,
+        };
+        if self.chance(self.options.dispute_ratio) {
+            if let Some((trans_type, client, tx)) = self.dispute_row() {
+                transaction.trans_type = trans_type;
+                transaction.client = ids::client_id(client);
+                transaction.tx = ids::tx_id(tx);
+                return Some(transaction)
+            }
+        }
+
+        let client = self.client();
+        transaction.client = ids::client_id(client);
+        match self.below(100) {
+            0..=54 => {
+                transaction.amount = Some(self.amount(10_000_000));
+                if self.deposits.len() == RECENT_DEPOSITS {
+                    let evicted = self.below(RECENT_DEPOSITS as u64) as usize;
+                    self.deposits.swap_remove(evicted);
+                }
+                self.deposits.push((client, tx));
+            },
+            85..=99 if self.options.clients > 1 => {
+                transaction.trans_type = TransactionType::Transfer;
+                transaction.amount = Some(self.amount(1_000_000));
+                let destination = self.destination(client);
+                transaction.destination = Some(ids::client_id(destination));
+            },
+            // A single client has nobody to transfer to, its transfers are withdrawals too.
+            _ => {
+                transaction.trans_type = TransactionType::WithDrawal;
+                transaction.amount = Some(self.amount(5_000_000));
+            },
+        }
+        Some(transaction)
+    }
+}
+
+pub async fn write(writer: impl AsyncWrite + Unpin, generator: Generator) -> Result<(), csv_async::Error> {
+    let mut serializer = AsyncWriterBuilder::new()
+        .delimiter(b',')
+        .create_serializer(writer);
+    for transaction in generator {
+        serializer.serialize(transaction).await?;
+    }
+    serializer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::generate::*;
+    use crate::csv_parser::deserialize_records;
+    use crate::ids::{self, client_id, ClientId};
+    use std::collections::HashSet;
+
+    fn options(transactions: u32, dispute_ratio: f64) -> GeneratorOptions {
+        GeneratorOptions { clients: 10, transactions: transactions.into(), dispute_ratio, seed: 7 }
+    }
+
+    #[test]
+    fn test_generator_is_deterministic() {
+        let first: Vec<String> = Generator::new(options(1000, 0.1)).map(|transaction| format!("{:?}", transaction)).collect();
+        let second: Vec<String> = Generator::new(options(1000, 0.1)).map(|transaction| format!("{:?}", transaction)).collect();
+        assert_eq!(first.len(), 1000);
+        assert_eq!(first, second);
+
+        let other: Vec<String> = Generator::new(GeneratorOptions { seed: 8, ..options(1000, 0.1) }).map(|transaction| format!("{:?}", transaction)).collect();
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn test_generator_disputes_reference_deposits() {
+        let mut deposits = HashSet::new();
+        let mut disputes = 0;
+        let mut last_time = 0;
+        for transaction in Generator::new(options(10000, 0.2)) {
+            assert!((1..=10).any(|client| transaction.client == client_id(client)));
+            assert!(transaction.timestamp.unwrap() >= last_time);
+            last_time = transaction.timestamp.unwrap();
+            match transaction.trans_type {
+                TransactionType::Deposit => { deposits.insert((transaction.client, transaction.tx)); },
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::ChargeBack => {
+                    disputes += 1;
+                    assert!(deposits.contains(&(transaction.client, transaction.tx)));
+                    assert_eq!(transaction.amount, None);
+                },
+                _ => assert!(transaction.amount.unwrap() > 0.0),
+            }
+        }
+        assert!(disputes > 1500 && disputes < 2500, "{}", disputes);
+
+        assert!(Generator::new(options(1000, 0.0)).all(|transaction| transaction.amount.is_some()));
+    }
+
+    #[test]
+    fn test_generator_covers_the_id_range() {
+        let clients = ids::MAX_CLIENT_NUMBER;
+        let number = |client: ClientId| client.to_string().parse::<u64>().unwrap();
+        let mut highest = 0;
+        for transaction in Generator::new(GeneratorOptions { clients, ..options(1000, 0.0) }) {
+            assert_ne!(transaction.client, ids::RESERVED_CLIENT);
+            assert_ne!(transaction.destination, Some(transaction.client));
+            highest = highest.max(number(transaction.client));
+        }
+        assert!(highest > clients / 2, "{}", highest);
+
+        for transaction in Generator::new(GeneratorOptions { clients: 2, ..options(1000, 0.0) }) {
+            if let Some(destination) = transaction.destination {
+                assert_eq!(number(destination), 3 - number(transaction.client));
+            }
+        }
+        assert!(Generator::new(GeneratorOptions { clients: 1, ..options(1000, 0.0) }).all(|transaction| transaction.destination.is_none()));
+    }
+
+    #[tokio::test]
+    async fn test_generated_csv_parses_back() {
+        let mut output = Vec::new();
+        write(&mut output, Generator::new(options(100, 0.1))).await.unwrap();
+
+        let parsed = deserialize_records::<Transaction>(output.as_slice()).await.unwrap();
+        let generated: Vec<Transaction> = Generator::new(options(100, 0.1)).collect();
+        assert_eq!(format!("{:?}", parsed), format!("{:?}", generated));
+    }
+}
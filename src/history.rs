@@ -0,0 +1,267 @@
+use crate::csv_parser::{TransactionType, MAX_PRECISION, deserialize_records};
+use crate::ids::{ClientId, TxId};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+use std::collections::HashMap;
+
+// Balances of one client right after an applied transaction.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct BalancePoint {
+    pub sequence: u64,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub trans_type: TransactionType,
+    pub timestamp: Option<u64>,
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+}
+
+impl BalancePoint {
+    fn time(&self) -> u64 {
+        self.timestamp.unwrap_or(self.sequence)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AsOf {
+    Tx(TxId),
+    Time(u64),
+}
+
+// Every SNAPSHOT_INTERVAL points of a client in event-time order its balance is kept, a time lookup only adds up
+// the changes after the nearest snapshot.
+const SNAPSHOT_INTERVAL: usize = 64;
+
+// What a point changed on its client, or the balance up to a point in event time. Accounts are never unlocked,
+// a point locks its client or leaves the lock as it was.
+#[derive(Debug, Copy, Clone, Default)]
+struct Change {
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+}
+
+impl Change {
+    fn add(&self, change: &Change) -> Change {
+        Change {
+            available: self.available + change.available,
+            held: self.held + change.held,
+            total: self.total + change.total,
+            locked: self.locked || change.locked,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ClientHistory {
+    // Indexes of the client's points in processing order and in event-time order; a row accepted late sorts in
+    // by its own time.
+    processed: Vec<usize>,
+    by_time: Vec<usize>,
+    // The balance after each SNAPSHOT_INTERVAL-th point of `by_time`.
+    snapshots: Vec<Change>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    points: Vec<BalancePoint>,
+    changes: Vec<Change>,
+    by_client: HashMap<ClientId, ClientHistory>,
+    // The last point of every tx.
+    by_tx: HashMap<TxId, usize>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    pub async fn load(reader: impl AsyncRead + Unpin + Send + Sync) -> Result<History, csv_async::Error> {
+        let mut history = History::new();
+        for point in deserialize_records::<BalancePoint>(reader).await? {
+            history.record(point);
+        }
+        Ok(history)
+    }
+
+    pub fn record(&mut self, point: BalancePoint) {
+        let index = self.points.len();
+        let client = self.by_client.entry(point.client).or_default();
+        let points = &self.points;
+        let previous = client.processed.last().map(|previous| &points[*previous]);
+        self.changes.push(Change {
+            available: point.available - previous.map_or(0.0, |previous| previous.available),
+            held: point.held - previous.map_or(0.0, |previous| previous.held),
+            total: point.total - previous.map_or(0.0, |previous| previous.total),
+            locked: point.locked && !previous.is_some_and(|previous| previous.locked),
+        });
+        client.processed.push(index);
+        let position = client.by_time.partition_point(|other| points[*other].time() <= point.time());
+        client.by_time.insert(position, index);
+        self.by_tx.insert(point.tx, index);
+        self.points.push(point);
+        let changes = &self.changes;
+
+        // A late point invalidates the snapshots after it.
+        client.snapshots.truncate(position / SNAPSHOT_INTERVAL);
+        while (client.snapshots.len() + 1) * SNAPSHOT_INTERVAL <= client.by_time.len() {
+            let start = client.snapshots.len() * SNAPSHOT_INTERVAL;
+            let base = client.snapshots.last().copied().unwrap_or_default();
+            let snapshot = client.by_time[start..start + SNAPSHOT_INTERVAL].iter()
+                .fold(base, |balance, index| balance.add(&changes[*index]));
+            client.snapshots.push(snapshot);
+        }
+    }
+
+    pub fn points(&self) -> &[BalancePoint] {
+        &self.points
+    }
+
+    pub fn clients(&self) -> Vec<ClientId> {
+        let mut clients: Vec<ClientId> = self.by_client.keys().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    // Balance of the client right after the requested tx, in processing order, or at the requested time.
+    // A tx referenced by several events (disputes, resolves...) resolves to its last one. At a time the balance
+    // holds the changes of every point up to that time, a row processed late included, and is reported on the
+    // latest of those points.
+    pub fn as_of(&self, client: ClientId, as_of: AsOf) -> Option<BalancePoint> {
+        let history = self.by_client.get(&client)?;
+        match as_of {
+            AsOf::Tx(tx) => {
+                let last = *self.by_tx.get(&tx)?;
+                let position = history.processed.partition_point(|index| *index <= last);
+                position.checked_sub(1).map(|position| self.points[history.processed[position]].clone())
+            },
+            AsOf::Time(time) => {
+                let position = history.by_time.partition_point(|index| self.points[*index].time() <= time);
+                let latest = history.by_time[..position].last()?;
+                let snapshot = position / SNAPSHOT_INTERVAL;
+                let base = snapshot.checked_sub(1).map_or_else(Change::default, |snapshot| history.snapshots[snapshot]);
+                let balance = history.by_time[snapshot * SNAPSHOT_INTERVAL..position].iter()
+                    .fold(base, |balance, index| balance.add(&self.changes[*index]));
+                Some(BalancePoint {
+                    available: settle(balance.available),
+                    held: settle(balance.held),
+                    total: settle(balance.total),
+                    locked: balance.locked,
+                    ..self.points[*latest].clone()
+                })
+            },
+        }
+    }
+}
+
+// Sums of changes drift in the last bits, balances never have more than MAX_PRECISION decimals.
+fn settle(amount: f64) -> f64 {
+    let scale = 10f64.powi(MAX_PRECISION as i32);
+    (amount * scale).round() / scale
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::File;
+    use crate::history::*;
+    use crate::ids::{client_id, tx_id};
+
+    fn point(sequence: u64, tx: u64, timestamp: Option<u64>, client: u64, available: f64) -> BalancePoint {
+        BalancePoint {
+            sequence,
+            tx: tx_id(tx),
+            trans_type: TransactionType::Deposit,
+            timestamp,
+            client: client_id(client),
+            available,
+            held: 0.0,
+            total: available,
+            locked: false,
+        }
+    }
+
+    #[test]
+    fn test_as_of_tx() {
+        let mut history = History::new();
+        history.record(point(1, 1, None, 1, 1.0));
+        history.record(point(2, 2, None, 2, 5.0));
+        history.record(point(3, 3, None, 1, 3.0));
+
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(1))).unwrap().available, 1.0);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(3))).unwrap().available, 3.0);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(2))).unwrap().available, 1.0);
+        assert_eq!(history.as_of(client_id(2), AsOf::Tx(tx_id(1))), None);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(4))), None);
+        assert_eq!(history.as_of(client_id(3), AsOf::Tx(tx_id(1))), None);
+        assert_eq!(history.clients(), [1, 2].map(client_id));
+    }
+
+    #[test]
+    fn test_as_of_time() {
+        let mut history = History::new();
+        history.record(point(1, 1, Some(1000), 1, 1.0));
+        history.record(point(2, 2, Some(2000), 1, 2.0));
+        history.record(point(3, 3, Some(3000), 1, 3.0));
+
+        assert_eq!(history.as_of(client_id(1), AsOf::Time(999)), None);
+        assert_eq!(history.as_of(client_id(1), AsOf::Time(1000)).unwrap().available, 1.0);
+        assert_eq!(history.as_of(client_id(1), AsOf::Time(2500)).unwrap().available, 2.0);
+        assert_eq!(history.as_of(client_id(1), AsOf::Time(9000)).unwrap().available, 3.0);
+    }
+
+    // Deposits of 1, 2 and 4 where the one at 1500 is processed last, accepted late.
+    #[test]
+    fn test_as_of_time_with_late_point() {
+        let mut history = History::new();
+        history.record(point(1, 1, Some(1000), 1, 1.0));
+        history.record(point(2, 2, Some(2000), 1, 3.0));
+        history.record(point(3, 3, Some(1500), 1, 7.0));
+
+        assert_eq!(history.as_of(client_id(1), AsOf::Time(1200)).unwrap().available, 1.0);
+        let late = history.as_of(client_id(1), AsOf::Time(1800)).unwrap();
+        assert_eq!(late.tx, tx_id(3));
+        assert_eq!(late.available, 5.0);
+        assert_eq!(late.total, 5.0);
+        let last = history.as_of(client_id(1), AsOf::Time(2500)).unwrap();
+        assert_eq!(last.tx, tx_id(2));
+        assert_eq!(last.available, 7.0);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(2))).unwrap().available, 3.0);
+    }
+
+    // Lookups across several snapshots, with late points landing before some of them.
+    #[test]
+    fn test_as_of_time_across_snapshots() {
+        let mut history = History::new();
+        let mut available = 0.0;
+        let mut times = Vec::new();
+        for sequence in 1..=300u64 {
+            // Every tenth deposit is stamped before the rows already processed.
+            let time = if sequence % 10 == 0 { 8000 + sequence * 100 } else { 10000 + sequence * 100 };
+            available += 0.1;
+            history.record(point(sequence, sequence, Some(time), 1, available));
+            times.push(time);
+        }
+
+        for at in [5000, 8950, 10150, 11000, 16450, 22345, 35000, 39950, 50000] {
+            let expected = times.iter().filter(|time| **time <= at).count() as f64 * 0.1;
+            let balance = history.as_of(client_id(1), AsOf::Time(at)).map_or(0.0, |point| point.available);
+            assert!((balance - expected).abs() < 1e-9, "at {}: {} != {}", at, balance, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_history_load() {
+        let file = File::open("test/history.csv").await.unwrap();
+        let history = History::load(file).await.unwrap();
+
+        assert_eq!(history.points().len(), 4);
+        let point = history.as_of(client_id(1), AsOf::Time(1627992002000)).unwrap();
+        assert_eq!(point.tx, tx_id(1));
+        assert_eq!(point.available, 0.0);
+        assert_eq!(point.held, 10.0);
+    }
+}
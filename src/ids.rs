@@ -0,0 +1,287 @@
+// Client and transaction ids. By default clients are u16 and transactions u32, as in the original input format;
+// the `wide-ids` feature makes both u64 and the `string-ids` feature takes any non-empty string, interned.
+// With both features enabled string ids win. Tests build their ids with `client_id` and `tx_id` so that they run
+// with every id type.
+
+#[cfg(not(any(feature = "wide-ids", feature = "string-ids")))]
+pub type ClientId = u16;
+#[cfg(not(any(feature = "wide-ids", feature = "string-ids")))]
+pub type TxId = u32;
+
+#[cfg(all(feature = "wide-ids", not(feature = "string-ids")))]
+pub type ClientId = u64;
+#[cfg(all(feature = "wide-ids", not(feature = "string-ids")))]
+pub type TxId = u64;
+
+#[cfg(feature = "string-ids")]
+pub use interned::Id;
+#[cfg(feature = "string-ids")]
+pub type ClientId = Id;
+#[cfg(feature = "string-ids")]
+pub type TxId = Id;
+
+// The default house account. A numeric build takes the largest client id, which is an ordinary client unless a
+// fee schedule is loaded and rows naming it are rejected; with string ids it is an id of its own that no input
+// string names.
+#[cfg(not(feature = "string-ids"))]
+pub const RESERVED_CLIENT: ClientId = ClientId::MAX;
+#[cfg(feature = "string-ids")]
+pub const RESERVED_CLIENT: ClientId = Id::House;
+
+// The largest numbers `client_id` and `tx_id` take; numbered clients stop below the house account.
+#[cfg(not(any(feature = "wide-ids", feature = "string-ids")))]
+pub const MAX_CLIENT_NUMBER: u64 = RESERVED_CLIENT as u64 - 1;
+#[cfg(not(any(feature = "wide-ids", feature = "string-ids")))]
+pub const MAX_TX_NUMBER: u64 = TxId::MAX as u64;
+
+#[cfg(all(feature = "wide-ids", not(feature = "string-ids")))]
+pub const MAX_CLIENT_NUMBER: u64 = RESERVED_CLIENT - 1;
+#[cfg(all(feature = "wide-ids", not(feature = "string-ids")))]
+pub const MAX_TX_NUMBER: u64 = u64::MAX;
+
+#[cfg(feature = "string-ids")]
+pub const MAX_CLIENT_NUMBER: u64 = u64::MAX;
+#[cfg(feature = "string-ids")]
+pub const MAX_TX_NUMBER: u64 = u64::MAX;
+
+#[cfg(not(feature = "string-ids"))]
+use std::convert::TryFrom;
+
+// Numbered ids for the synthetic data generator and the tests. A number the id type cannot hold is a bug of the
+// caller, it panics instead of wrapping around to another id.
+#[cfg(not(feature = "string-ids"))]
+pub fn client_id(number: u64) -> ClientId {
+    ClientId::try_from(number).unwrap_or_else(|_| panic!("client id {} out of range", number))
+}
+
+#[cfg(not(feature = "string-ids"))]
+pub fn tx_id(number: u64) -> TxId {
+    TxId::try_from(number).unwrap_or_else(|_| panic!("transaction id {} out of range", number))
+}
+
+#[cfg(feature = "string-ids")]
+pub fn client_id(number: u64) -> ClientId {
+    Id::new(&number.to_string())
+}
+
+#[cfg(feature = "string-ids")]
+pub fn tx_id(number: u64) -> TxId {
+    Id::new(&number.to_string())
+}
+
+// Log field of an id, numeric ids keep their number type in structured logs.
+#[cfg(not(feature = "string-ids"))]
+pub fn field(id: impl Into<u64>) -> u64 {
+    id.into()
+}
+
+#[cfg(feature = "string-ids")]
+pub fn field(id: Id) -> &'static str {
+    id.as_str()
+}
+
+#[cfg(feature = "string-ids")]
+mod interned {
+    use serde::de::{self, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::cmp::Ordering;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::fmt::{Debug, Display, Formatter};
+    use std::str::FromStr;
+    use std::sync::{Mutex, OnceLock};
+
+    // Every distinct id string is stored once for the life of the process; accounts, records, ledger entries
+    // and indexes only carry its handle. Ids order by their string, the house account first.
+    #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+    pub enum Id {
+        // The house account, apart from every input id including "house", which is only its name in the output.
+        House,
+        Named(Handle),
+    }
+
+    // Only the interner hands out handles, so every handle has a name.
+    #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+    pub struct Handle(u32);
+
+    const HOUSE_NAME: &str = "house";
+    const BUCKETS: usize = 32;
+
+    // Bucket `b` holds the names of the handles 2^b - 1 to 2^(b+1) - 2. A bucket is allocated once and a name is
+    // set once, before its handle is handed out, so names are read without a lock.
+    type Bucket = OnceLock<Box<[OnceLock<Box<str>>]>>;
+    static NAMES: [Bucket; BUCKETS] = [const { OnceLock::new() }; BUCKETS];
+
+    fn slot(handle: u32) -> (usize, usize) {
+        let index = handle as u64 + 1;
+        let bucket = index.ilog2();
+        (bucket as usize, (index - (1 << bucket)) as usize)
+    }
+
+    fn name(handle: u32) -> &'static str {
+        let (bucket, offset) = slot(handle);
+        NAMES[bucket].get().and_then(|names| names[offset].get()).expect("interned id without a name")
+    }
+
+    // The handle after `taken` others; the last u32 would need a 33rd bucket.
+    pub(super) fn next_handle(taken: usize) -> Option<u32> {
+        u32::try_from(taken).ok().filter(|handle| *handle < u32::MAX)
+    }
+
+    // Interning is the only writer, it takes the lock to look the string up and to add it. Once every handle is
+    // taken a new string is an error, the row naming it is rejected and the ids already interned keep working.
+    fn intern(name: &str) -> Result<u32, String> {
+        static HANDLES: OnceLock<Mutex<HashMap<&'static str, u32>>> = OnceLock::new();
+        let mut handles = HANDLES.get_or_init(Default::default).lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(handle) = handles.get(name) {
+            return Ok(*handle)
+        }
+        let handle = next_handle(handles.len()).ok_or_else(|| format!("Too many distinct ids, {} cannot be added", name))?;
+        let (bucket, offset) = slot(handle);
+        let names = NAMES[bucket].get_or_init(|| (0..1usize << bucket).map(|_| OnceLock::new()).collect());
+        let stored: &'static str = names[offset].get_or_init(|| name.into());
+        handles.insert(stored, handle);
+        Ok(handle)
+    }
+
+    impl Id {
+        // Numbered ids of the generator and the tests; input ids are parsed, which reports a full interner.
+        pub fn new(name: &str) -> Id {
+            name.parse().unwrap_or_else(|err| panic!("{}", err))
+        }
+
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                Id::House => HOUSE_NAME,
+                Id::Named(handle) => name(handle.0),
+            }
+        }
+    }
+
+    impl FromStr for Id {
+        type Err = String;
+
+        fn from_str(name: &str) -> Result<Self, Self::Err> {
+            if name.is_empty() {
+                return Err("Empty id".to_owned())
+            }
+            Ok(Id::Named(Handle(intern(name)?)))
+        }
+    }
+
+    impl Display for Id {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}", self.as_str())
+        }
+    }
+
+    impl Debug for Id {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{:?}", self.as_str())
+        }
+    }
+
+    impl Ord for Id {
+        fn cmp(&self, other: &Self) -> Ordering {
+            match (self, other) {
+                (Id::House, Id::House) => Ordering::Equal,
+                (Id::House, Id::Named(_)) => Ordering::Less,
+                (Id::Named(_), Id::House) => Ordering::Greater,
+                (Id::Named(handle), Id::Named(other)) if handle == other => Ordering::Equal,
+                (Id::Named(handle), Id::Named(other)) => name(handle.0).cmp(name(other.0)),
+            }
+        }
+    }
+
+    impl PartialOrd for Id {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Serialize for Id {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(self.as_str())
+        }
+    }
+
+    struct IdVisitor;
+
+    impl<'de> Visitor<'de> for IdVisitor {
+        type Value = Id;
+
+        fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "a non-empty id")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Id, E> {
+            value.parse().map_err(E::custom)
+        }
+
+        fn visit_u64<E: de::Error>(self, value: u64) -> Result<Id, E> {
+            self.visit_str(&value.to_string())
+        }
+
+        fn visit_i64<E: de::Error>(self, value: i64) -> Result<Id, E> {
+            self.visit_str(&value.to_string())
+        }
+    }
+
+    // CSV fields are taken as written, "007" stays "007". Formats that know a number is not a string, as TOML does,
+    // still hand it over, so configuration files written for numeric ids keep loading.
+    impl<'de> Deserialize<'de> for Id {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Id, D::Error> {
+            deserializer.deserialize_str(IdVisitor)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::*;
+
+    #[test]
+    fn test_numbered_ids() {
+        assert_eq!(client_id(7).to_string(), "7");
+        assert_eq!(tx_id(123456).to_string(), "123456");
+        assert_ne!(client_id(1), RESERVED_CLIENT);
+        assert_ne!(client_id(MAX_CLIENT_NUMBER), RESERVED_CLIENT);
+        assert_eq!(tx_id(MAX_TX_NUMBER).to_string(), MAX_TX_NUMBER.to_string());
+    }
+
+    #[cfg(feature = "string-ids")]
+    #[test]
+    fn test_interned_ids() {
+        let first = Id::new("customer-42");
+        assert_eq!(Id::new("customer-42"), first);
+        assert_ne!(Id::new("customer-43"), first);
+        assert_eq!(first.as_str(), "customer-42");
+        assert_eq!(RESERVED_CLIENT.as_str(), "house");
+        assert_ne!(Id::new("house"), RESERVED_CLIENT);
+        assert!(RESERVED_CLIENT < Id::new("a"));
+        assert!(Id::new("a") < Id::new("b"));
+        assert!("".parse::<Id>().is_err());
+        assert_eq!(interned::next_handle(0), Some(0));
+        assert_eq!(interned::next_handle(u32::MAX as usize - 1), Some(u32::MAX - 1));
+        assert_eq!(interned::next_handle(u32::MAX as usize), None);
+
+        let parsed: Vec<Id> = serde_json::from_str("[\"customer-42\", \"007\"]").unwrap();
+        assert_eq!(parsed, vec![first, Id::new("007")]);
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), "[\"customer-42\",\"007\"]");
+        let config: std::collections::HashMap<String, Id> = toml::from_str("house_account = 999").unwrap();
+        assert_eq!(config["house_account"], Id::new("999"));
+    }
+
+    #[cfg(feature = "string-ids")]
+    #[tokio::test]
+    async fn test_string_ids_in_input() {
+        use crate::csv_parser::{deserialize_records, Transaction};
+
+        let input = "type, client, tx, amount, destination\ndeposit, alice, tx-1, 1.0,\ntransfer, alice, 007, 0.5, bob\n";
+        let transactions = deserialize_records::<Transaction>(input.as_bytes()).await.unwrap();
+        assert_eq!(transactions[0].client, Id::new("alice"));
+        assert_eq!(transactions[0].tx.as_str(), "tx-1");
+        assert_eq!(transactions[1].tx.as_str(), "007");
+        assert_eq!(transactions[1].destination, Some(Id::new("bob")));
+    }
+}
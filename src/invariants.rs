@@ -0,0 +1,113 @@
+use crate::csv_parser::TransactionType;
+use crate::ids::{ClientId, TxId};
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Invariant {
+    TotalMatchesLedger,
+    HeldIsNotNegative,
+    LockedAccountFollowsPolicy,
+}
+
+pub const INVARIANTS: [Invariant; 3] = [
+    Invariant::TotalMatchesLedger,
+    Invariant::HeldIsNotNegative,
+    Invariant::LockedAccountFollowsPolicy,
+];
+
+impl Display for Invariant {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Invariant::TotalMatchesLedger => {write!(f, "total equals the ledger balance")}
+            Invariant::HeldIsNotNegative => {write!(f, "held is never negative")}
+            Invariant::LockedAccountFollowsPolicy => {write!(f, "locked account only changes as the lock policy allows")}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    pub locked: bool,
+    // The sum of the client's Available and Held postings, which the reported total has to match.
+    pub ledger: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub invariant: Invariant,
+    pub tx: TxId,
+    pub trans_type: TransactionType,
+    pub before: Option<Snapshot>,
+    pub after: Snapshot,
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invariant '{}' violated by {:?} tx {} on client {}: before {:?}, after {:?}",
+               self.invariant, self.trans_type, self.tx, self.after.client, self.before, self.after)
+    }
+}
+
+impl Invariant {
+    // `lock_allows` tells whether the configured lock policy lets the transaction change a locked account.
+    pub fn holds(&self, before: Option<&Snapshot>, after: &Snapshot, lock_allows: bool) -> bool {
+        match self {
+            Invariant::TotalMatchesLedger => {
+                ((after.ledger - after.total) * 10000.0).round() == 0.0
+            }
+            Invariant::HeldIsNotNegative => after.held >= 0.0,
+            Invariant::LockedAccountFollowsPolicy => match before {
+                Some(before) if before.locked && !lock_allows => before == after,
+                _ => true,
+            },
+        }
+    }
+}
+
+pub fn check(before: Option<&Snapshot>, after: &Snapshot, lock_allows: bool) -> Vec<Invariant> {
+    INVARIANTS.iter()
+        .filter(|invariant| !invariant.holds(before, after, lock_allows))
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::client_id;
+    use crate::invariants::*;
+
+    fn snapshot(available: f64, held: f64, total: f64, locked: bool) -> Snapshot {
+        Snapshot { client: client_id(1), available, held, total, locked, ledger: available + held }
+    }
+
+    #[test]
+    fn test_check_valid() {
+        assert!(check(None, &snapshot(1.0, 2.0, 3.0, false), true).is_empty());
+        assert!(check(Some(&snapshot(1.0, 0.0, 1.0, true)), &snapshot(1.0, 0.0, 1.0, true), false).is_empty());
+    }
+
+    #[test]
+    fn test_check_total() {
+        assert_eq!(check(None, &snapshot(1.0, 2.0, 4.0, false), true), vec![Invariant::TotalMatchesLedger]);
+        let stale = Snapshot { ledger: 4.0, ..snapshot(1.0, 2.0, 3.0, false) };
+        assert_eq!(check(None, &stale, true), vec![Invariant::TotalMatchesLedger]);
+    }
+
+    #[test]
+    fn test_check_held_negative() {
+        assert_eq!(check(None, &snapshot(2.0, -1.0, 1.0, false), true), vec![Invariant::HeldIsNotNegative]);
+    }
+
+    #[test]
+    fn test_check_locked_account_changed() {
+        let before = snapshot(1.0, 0.0, 1.0, true);
+
+        assert_eq!(check(Some(&before), &snapshot(2.0, 0.0, 2.0, true), false), vec![Invariant::LockedAccountFollowsPolicy]);
+        assert!(check(Some(&before), &snapshot(2.0, 0.0, 2.0, true), true).is_empty());
+        assert!(check(Some(&snapshot(1.0, 0.0, 1.0, false)), &snapshot(0.0, 0.0, 0.0, true), false).is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use crate::csv_parser::TransactionType;
+use crate::ids::{ClientId, TxId};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum LedgerAccount {
+    Available(ClientId),
+    Held(ClientId),
+    Settlement,
+    ChargebackLoss,
+}
+
+impl Display for LedgerAccount {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerAccount::Available(client) => {write!(f, "available:{}", client)}
+            LedgerAccount::Held(client) => {write!(f, "held:{}", client)}
+            LedgerAccount::Settlement => {write!(f, "settlement")}
+            LedgerAccount::ChargebackLoss => {write!(f, "chargeback_loss")}
+        }
+    }
+}
+
+impl Serialize for LedgerAccount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.collect_str(self)
+    }
+}
+
+// Credits increase and debits decrease the balance of a ledger account, so every posting nets to zero.
+#[derive(Serialize, Debug, Clone)]
+pub struct Posting {
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub trans_type: TransactionType,
+    pub fee: bool,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: f64,
+}
+
+#[derive(Debug, Default)]
+pub struct Ledger {
+    postings: Vec<Posting>,
+    balances: HashMap<LedgerAccount, f64>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn post(&mut self, tx: TxId, trans_type: TransactionType, debit: LedgerAccount, credit: LedgerAccount, amount: f64) {
+        self.record(Posting { tx, trans_type, fee: false, debit, credit, amount });
+    }
+
+    pub fn post_fee(&mut self, tx: TxId, trans_type: TransactionType, debit: LedgerAccount, credit: LedgerAccount, amount: f64) {
+        self.record(Posting { tx, trans_type, fee: true, debit, credit, amount });
+    }
+
+    fn record(&mut self, posting: Posting) {
+        *self.balances.entry(posting.debit).or_insert(0.0) -= posting.amount;
+        *self.balances.entry(posting.credit).or_insert(0.0) += posting.amount;
+        self.postings.push(posting);
+    }
+
+    pub fn balance(&self, account: LedgerAccount) -> f64 {
+        self.balances.get(&account).copied().unwrap_or(0.0)
+    }
+
+    pub fn postings(&self) -> &[Posting] {
+        &self.postings
+    }
+
+    // Sum of every ledger account balance, rounded to the input precision. Balanced books give zero.
+    pub fn trial_balance(&self) -> f64 {
+        let sum: f64 = self.balances.values().sum();
+        (sum * 10000.0).round() / 10000.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ids::{client_id, tx_id};
+    use crate::ledger::*;
+
+    #[test]
+    fn test_post() {
+        let mut ledger = Ledger::new();
+
+        ledger.post(tx_id(1), TransactionType::Deposit, LedgerAccount::Settlement, LedgerAccount::Available(client_id(1)), 2.5);
+        ledger.post(tx_id(1), TransactionType::Dispute, LedgerAccount::Available(client_id(1)), LedgerAccount::Held(client_id(1)), 1.0);
+
+        assert_eq!(ledger.balance(LedgerAccount::Available(client_id(1))), 1.5);
+        assert_eq!(ledger.balance(LedgerAccount::Held(client_id(1))), 1.0);
+        assert_eq!(ledger.balance(LedgerAccount::Settlement), -2.5);
+        assert_eq!(ledger.balance(LedgerAccount::Available(client_id(2))), 0.0);
+        assert_eq!(ledger.postings().len(), 2);
+        assert_eq!(ledger.trial_balance(), 0.0);
+    }
+
+    #[test]
+    fn test_post_fee() {
+        let mut ledger = Ledger::new();
+
+        ledger.post_fee(tx_id(1), TransactionType::WithDrawal, LedgerAccount::Available(client_id(1)), LedgerAccount::Available(client_id(2)), 0.1);
+
+        assert!(ledger.postings()[0].fee);
+        assert_eq!(ledger.trial_balance(), 0.0);
+    }
+
+    #[test]
+    fn test_ledger_account_display() {
+        assert_eq!(LedgerAccount::Available(client_id(3)).to_string(), "available:3");
+        assert_eq!(LedgerAccount::Held(client_id(3)).to_string(), "held:3");
+        assert_eq!(LedgerAccount::Settlement.to_string(), "settlement");
+        assert_eq!(LedgerAccount::ChargebackLoss.to_string(), "chargeback_loss");
+    }
+}
@@ -0,0 +1,18 @@
+pub mod config;
+pub mod csv_parser;
+pub mod directory;
+pub mod error;
+pub mod events;
+pub mod fee;
+pub mod generate;
+pub mod history;
+pub mod ids;
+pub mod invariants;
+pub mod ledger;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod options;
+pub mod replay;
+pub mod rules;
+pub mod transaction_manager;
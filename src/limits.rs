@@ -0,0 +1,214 @@
+use crate::csv_parser::{Transaction, TransactionType, TransactionError, deserialize_records};
+use crate::ids::ClientId;
+use serde::Deserialize;
+use tokio::io::AsyncRead;
+use std::collections::{HashMap, VecDeque};
+
+pub const DEFAULT_TIER: &str = "default";
+
+// Limits of one client tier. `window` is measured in the transaction time unit: epoch millis
+// when the input has a timestamp column, row sequence numbers otherwise.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TierLimits {
+    pub tier: String,
+    pub max_withdrawal: Option<f64>,
+    pub window: Option<u64>,
+    pub max_window_withdrawal: Option<f64>,
+    pub max_window_transactions: Option<usize>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ClientTier {
+    pub client: ClientId,
+    pub tier: String,
+}
+
+#[derive(Debug, Default)]
+pub struct RiskControls {
+    tiers: HashMap<String, TierLimits>,
+    clients: HashMap<ClientId, String>,
+    client_limits: HashMap<ClientId, f64>,
+    activity: HashMap<ClientId, VecDeque<(u64, f64)>>,
+}
+
+fn outgoing_amount(transaction: &Transaction) -> f64 {
+    match transaction.trans_type {
+        TransactionType::WithDrawal | TransactionType::Transfer => transaction.amount.unwrap_or(0.0),
+        _ => 0.0,
+    }
+}
+
+impl RiskControls {
+    pub fn new(tiers: Vec<TierLimits>, clients: Vec<ClientTier>) -> RiskControls {
+        RiskControls {
+            tiers: tiers.into_iter().map(|limits| (limits.tier.clone(), limits)).collect(),
+            clients: clients.into_iter().map(|client| (client.client, client.tier)).collect(),
+            client_limits: HashMap::new(),
+            activity: HashMap::new(),
+        }
+    }
+
+    // Joins a client of the accounts master file: its tier replaces the one of the tiers file.
+    pub fn assign(&mut self, client: ClientId, tier: Option<&str>, max_withdrawal: Option<f64>) {
+        if let Some(tier) = tier {
+            self.clients.insert(client, tier.to_owned());
+        }
+        if let Some(max_withdrawal) = max_withdrawal {
+            self.client_limits.insert(client, max_withdrawal);
+        }
+    }
+
+    pub async fn load(tiers: impl AsyncRead + Unpin + Send + Sync, clients: Option<impl AsyncRead + Unpin + Send + Sync>) -> Result<RiskControls, csv_async::Error> {
+        let tiers = deserialize_records::<TierLimits>(tiers).await?;
+        let clients = match clients {
+            Some(clients) => deserialize_records::<ClientTier>(clients).await?,
+            None => Vec::new(),
+        };
+        Ok(RiskControls::new(tiers, clients))
+    }
+
+    fn limits(&self, client: ClientId) -> Option<&TierLimits> {
+        let tier = self.clients.get(&client).map_or(DEFAULT_TIER, |tier| tier.as_str());
+        self.tiers.get(tier)
+    }
+
+    pub fn check(&mut self, transaction: &Transaction) -> Result<(), TransactionError> {
+        let amount = outgoing_amount(transaction);
+        if self.client_limits.get(&transaction.client).is_some_and(|max_withdrawal| amount > *max_withdrawal) {
+            return Err(TransactionError::WithdrawalLimitExceeded)
+        }
+        let limits = match self.limits(transaction.client) {
+            Some(limits) => limits.clone(),
+            None => return Ok(()),
+        };
+
+        if let Some(max_withdrawal) = limits.max_withdrawal {
+            if amount > max_withdrawal {
+                return Err(TransactionError::WithdrawalLimitExceeded)
+            }
+        }
+
+        if let Some(window) = limits.window {
+            let now = transaction.time();
+            let activity = self.activity.entry(transaction.client).or_default();
+            while activity.front().is_some_and(|(time, _)| time.saturating_add(window) <= now) {
+                activity.pop_front();
+            }
+
+            if let Some(max_window_transactions) = limits.max_window_transactions {
+                if activity.len() >= max_window_transactions {
+                    return Err(TransactionError::TransactionRateExceeded)
+                }
+            }
+            if let Some(max_window_withdrawal) = limits.max_window_withdrawal {
+                let withdrawn: f64 = activity.iter().map(|(_, amount)| amount).sum();
+                if amount > 0.0 && withdrawn + amount > max_window_withdrawal {
+                    return Err(TransactionError::WindowWithdrawalLimitExceeded)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Only accepted transactions count towards the window limits.
+    pub fn record(&mut self, transaction: &Transaction) {
+        if self.limits(transaction.client).is_some_and(|limits| limits.window.is_some()) {
+            self.activity.entry(transaction.client)
+                .or_default()
+                .push_back((transaction.time(), outgoing_amount(transaction)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::File;
+    use crate::limits::*;
+    use crate::ids::{client_id, tx_id};
+
+    fn withdrawal(client: u64, tx: u64, amount: f64, timestamp: u64) -> Transaction {
+        Transaction {
+            client: client_id(client),
+            trans_type: TransactionType::WithDrawal,
+            tx: tx_id(tx),
+            amount: Some(amount),
+            destination: None,
+            timestamp: Some(timestamp),
+            sequence: 0,
+        }
+    }
+
+    fn tier(tier: &str, max_withdrawal: Option<f64>, window: Option<u64>, max_window_withdrawal: Option<f64>, max_window_transactions: Option<usize>) -> TierLimits {
+        TierLimits { tier: tier.to_owned(), max_withdrawal, window, max_window_withdrawal, max_window_transactions }
+    }
+
+    fn apply(controls: &mut RiskControls, transaction: &Transaction) -> Result<(), TransactionError> {
+        controls.check(transaction)?;
+        controls.record(transaction);
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_withdrawal() {
+        let mut controls = RiskControls::new(vec![tier(DEFAULT_TIER, Some(10.0), None, None, None)], Vec::new());
+
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 1, 10.0, 0)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 2, 10.5, 0)), Err(TransactionError::WithdrawalLimitExceeded)));
+    }
+
+    #[test]
+    fn test_window_withdrawal_total() {
+        let mut controls = RiskControls::new(vec![tier(DEFAULT_TIER, None, Some(100), Some(10.0), None)], Vec::new());
+
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 1, 6.0, 0)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 2, 6.0, 50)), Err(TransactionError::WindowWithdrawalLimitExceeded)));
+        assert!(matches!(apply(&mut controls, &withdrawal(2, 3, 6.0, 50)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 4, 6.0, 100)), Ok(())));
+    }
+
+    #[test]
+    fn test_window_transaction_count() {
+        let mut controls = RiskControls::new(vec![tier(DEFAULT_TIER, None, Some(10), None, Some(2))], Vec::new());
+
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 1, 1.0, 0)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 2, 1.0, 1)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 3, 1.0, 2)), Err(TransactionError::TransactionRateExceeded)));
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 4, 1.0, 10)), Ok(())));
+    }
+
+    #[test]
+    fn test_client_tier() {
+        let mut controls = RiskControls::new(
+            vec![tier(DEFAULT_TIER, Some(10.0), None, None, None), tier("gold", Some(100.0), None, None, None)],
+            vec![ClientTier { client: client_id(2), tier: "gold".to_owned() }]);
+
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 1, 50.0, 0)), Err(TransactionError::WithdrawalLimitExceeded)));
+        assert!(matches!(apply(&mut controls, &withdrawal(2, 2, 50.0, 0)), Ok(())));
+    }
+
+    #[test]
+    fn test_assigned_client() {
+        let mut controls = RiskControls::new(
+            vec![tier(DEFAULT_TIER, Some(10.0), None, None, None), tier("gold", Some(100.0), None, None, None)],
+            vec![ClientTier { client: client_id(2), tier: "gold".to_owned() }]);
+        controls.assign(client_id(1), Some("gold"), None);
+        controls.assign(client_id(2), None, Some(20.0));
+        controls.assign(client_id(3), Some("unknown"), Some(5.0));
+
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 1, 50.0, 0)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(2, 2, 50.0, 0)), Err(TransactionError::WithdrawalLimitExceeded)));
+        assert!(matches!(apply(&mut controls, &withdrawal(2, 3, 20.0, 0)), Ok(())));
+        assert!(matches!(apply(&mut controls, &withdrawal(3, 4, 6.0, 0)), Err(TransactionError::WithdrawalLimitExceeded)));
+        assert!(matches!(apply(&mut controls, &withdrawal(3, 5, 5.0, 0)), Ok(())));
+    }
+
+    #[tokio::test]
+    async fn test_risk_controls_load() {
+        let tiers = File::open("test/limits.csv").await.unwrap();
+        let clients = File::open("test/tiers.csv").await.unwrap();
+        let mut controls = RiskControls::load(tiers, Some(clients)).await.unwrap();
+
+        assert!(matches!(apply(&mut controls, &withdrawal(1, 1, 150.0, 0)), Err(TransactionError::WithdrawalLimitExceeded)));
+        assert!(matches!(apply(&mut controls, &withdrawal(2, 2, 150.0, 0)), Ok(())));
+    }
+}
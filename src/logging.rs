@@ -0,0 +1,72 @@
+use std::env;
+use std::io::IsTerminal;
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+
+pub const LOG_ENV: &str = "TOY_ENGINE_LOG";
+const DEFAULT_LEVEL: &str = "info";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}, expected text or json", format)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogOptions {
+    pub level: Option<String>,
+    pub format: LogFormat,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        LogOptions { level: None, format: LogFormat::Text }
+    }
+}
+
+// The level comes from --log-level, then TOY_ENGINE_LOG, and takes any env-filter directive such as `toy_engine=debug`.
+fn filter(options: &LogOptions) -> Result<EnvFilter, String> {
+    let directives = match &options.level {
+        Some(level) => level.clone(),
+        None => env::var(LOG_ENV).unwrap_or_else(|_| DEFAULT_LEVEL.to_owned()),
+    };
+    EnvFilter::try_new(&directives).map_err(|err| format!("Invalid log level {}: {}", directives, err))
+}
+
+// Logs go to stderr, stdout is kept for the CSV output.
+pub fn init(options: &LogOptions) -> Result<(), String> {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter(options)?)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match options.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }.map_err(|err| format!("Cannot initialize logging {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::*;
+
+    #[test]
+    fn test_filter() {
+        assert!(filter(&LogOptions { level: Some("debug".to_owned()), format: LogFormat::Text }).is_ok());
+        assert!(filter(&LogOptions { level: Some("toy_engine=trace,warn".to_owned()), format: LogFormat::Json }).is_ok());
+        assert!(filter(&LogOptions { level: Some("toy_engine=loud".to_owned()), format: LogFormat::Text }).is_err());
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
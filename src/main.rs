@@ -1,47 +1,623 @@
 use std::env;
-use tokio::io::{self};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::io::{self, AsyncWrite};
 use tokio::fs::File;
-use tokio::sync::mpsc::channel;
+use tokio::sync::mpsc::{channel, unbounded_channel, UnboundedReceiver};
 use csv_async::{AsyncWriterBuilder};
-use std::collections::HashMap;
-
-mod csv_parser;
-mod transaction_manager;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use toy_engine::error::EngineError;
+use toy_engine::{config, csv_parser, directory, events, fee, generate, history, ids, limits, logging, metrics, options, replay, rules, transaction_manager};
 
+// Usage and engine errors exit with 2, replay differences with 1, a run stopped by a second shutdown signal with 130.
 #[tokio::main]
 async fn main() {
-    if let Some(input_file) = env::args().collect::<Vec<String>>().get(1) {
-        match File::open(input_file).await {
+    let (log, args) = match options::parse_logging(&env::args().skip(1).collect::<Vec<String>>()) {
+        Ok(parsed) => parsed,
+        Err(err) => usage_error(err),
+    };
+    if let Err(err) = logging::init(&log) {
+        eprintln!("{}", err);
+        std::process::exit(2)
+    }
+
+    let result = match options::parse_command(&args).await {
+        Ok(options::Command::Process(options)) => process(*options).await,
+        Ok(options::Command::AsOf(options)) => query_as_of(options).await,
+        Ok(options::Command::Rebuild(options)) => rebuild(options).await,
+        Ok(options::Command::Replay(options)) => replay(options).await,
+        Ok(options::Command::Generate(options)) => generate_csv(options).await,
+        Ok(options::Command::PrintConfig(options)) => print_config(&options),
+        Err(err) => usage_error(err),
+    };
+    match result {
+        Ok(()) => {},
+        Err(EngineError::Interrupted) => {
+            warn!("{}", EngineError::Interrupted);
+            std::process::exit(130)
+        },
+        Err(err) => {
+            error!(error = %err, "engine error");
+            std::process::exit(2)
+        },
+    }
+}
+
+fn usage_error(err: String) -> ! {
+    eprintln!("{}", err);
+    eprintln!("{}", options::USAGE);
+    std::process::exit(2)
+}
+
+async fn process(options: options::Options) -> Result<(), EngineError> {
+    let metrics = if options.metrics_addr.is_some() || options.metrics_file.is_some() {
+        Some(Arc::new(Mutex::new(metrics::Metrics::new())))
+    } else {
+        None
+    };
+    if let (Some(addr), Some(metrics)) = (&options.metrics_addr, &metrics) {
+        match TcpListener::bind(addr).await {
+            Ok(listener) => {
+                info!(addr = %addr, "serving metrics");
+                tokio::spawn(metrics::serve(listener, metrics.clone()));
+            },
+            Err(err) => return Err(EngineError::Bind { addr: addr.to_owned(), source: err }),
+        }
+    }
+
+    let (manager, outcomes) = run(&options, options.outcomes.is_some(), metrics.clone()).await?;
+
+    if !manager.violations().is_empty() {
+        warn!(violations = manager.violations().len(), "audit found invariant violations");
+    }
+    let trial_balance = manager.ledger().trial_balance();
+    if trial_balance != 0.0 {
+        error!(trial_balance, "ledger is out of balance");
+    }
+    if let Some(alerts) = &options.alerts {
+        write_records(alerts, manager.alerts().iter()).await;
+    }
+    if let Some(ledger) = &options.ledger {
+        write_records(ledger, manager.ledger().postings().iter()).await;
+    }
+    if let Some(fee_ledger) = &options.fee_ledger {
+        write_records(fee_ledger, manager.ledger().postings().iter().filter(|posting| posting.fee)).await;
+    }
+    if let (Some(path), Some(history)) = (&options.history, manager.history()) {
+        write_records(path, history.points().iter()).await;
+    }
+    if let (Some(path), Some(stream)) = (&options.events, manager.events()) {
+        match File::create(path).await {
             Ok(file) => {
-                let (tx, mut rx) = channel(100);
+                if let Err(err) = events::write(file, options.events_format, events::per_client(stream)).await {
+                    error!(file = %path, error = %err, "cannot write output file");
+                }
+            },
+            Err(err) => { error!(file = %path, error = %err, "cannot create output file"); }
+        }
+    }
+    if let Some(path) = &options.outcomes {
+        write_records(path, outcomes.iter()).await;
+    }
+    if let (Some(path), Some(metrics)) = (&options.metrics_file, &metrics) {
+        let text = metrics.lock().map(|metrics| metrics.render()).unwrap_or_default();
+        if let Err(err) = tokio::fs::write(path, text).await {
+            error!(file = %path, error = %err, "cannot write output file");
+        }
+    }
+
+    if let Err(err) = write_accounts(io::stdout(), options.output_delimiter, manager.client_accounts(), options.last_activity).await {
+        error!(error = %err, "cannot write output");
+    }
+    Ok(())
+}
+
+async fn write_accounts<'a>(writer: impl AsyncWrite + Unpin, delimiter: u8, accounts: impl Iterator<Item = &'a transaction_manager::Account>, last_activity: bool) -> Result<(), csv_async::Error> {
+    let mut serializer = AsyncWriterBuilder::new()
+        .delimiter(delimiter)
+        .create_serializer(writer);
+
+    for account in accounts {
+        let result = if last_activity {
+            serializer.serialize(account.activity()).await
+        } else {
+            serializer.serialize(account).await
+        };
+        if result.is_err(){
+            error!("unable to serialize record");
+        }
+    }
+    serializer.flush().await?;
+    Ok(())
+}
 
-                tokio::spawn(async move {
-                    csv_parser::deserialize_csv(tx, file).await;
-                });
+// Feeds the whole input through a configured manager, optionally keeping the outcome of every row.
+async fn run(options: &options::Options, record_outcomes: bool, metrics: Option<metrics::SharedMetrics>) -> Result<(transaction_manager::TransactionManager, Vec<replay::Outcome>), EngineError> {
+    run_until(options, record_outcomes, metrics, shutdown_signals()).await
+}
+
+// The first shutdown signal drains the queued transactions and ends the run normally, the second one abandons it.
+async fn run_until(options: &options::Options, record_outcomes: bool, metrics: Option<metrics::SharedMetrics>, mut signals: UnboundedReceiver<()>) -> Result<(transaction_manager::TransactionManager, Vec<replay::Outcome>), EngineError> {
+    let fees = load_fees(options).await?;
+    let risk_controls = load_risk_controls(options).await?;
+    let rules = load_rules(options).await?;
+    let directory = load_directory(options).await?;
+    let file = File::open(&options.input).await.map_err(|err| EngineError::open(&options.input, err))?;
+
+    let (tx, mut rx) = channel(options.queue_depth);
+    let (shed_tx, mut shed) = unbounded_channel();
+
+    let input = info_span!("input", file = %options.input);
+    let format = options.input_format;
+    let ordering = options.ordering;
+    let admission = options.admission;
+    tokio::spawn(async move {
+        if let Err(err) = csv_parser::deserialize_csv(tx, shed_tx, file, format, ordering, admission).await {
+            info!(error = %err, "input not read to the end");
+        }
+    }.instrument(input.clone()));
 
-                let mut accounts = HashMap::new();
+    let mut manager = transaction_manager::TransactionManager::new(fees);
+    manager.set_dispute_policy(options.dispute_policy);
+    manager.set_lock_policy(options.lock_policy);
+    manager.set_risk_controls(risk_controls);
+    manager.set_directory(directory);
+    for rule in rules {
+        manager.add_rule(rule);
+    }
+    if options.audit || env::var_os("TOY_ENGINE_AUDIT").is_some() {
+        manager.enable_audit();
+    }
+    if options.history.is_some() {
+        manager.enable_history();
+    }
+    if options.events.is_some() {
+        manager.enable_events();
+    }
 
-                while let Some(message) = rx.recv().await {
-                    let result = transaction_manager::process_transaction(&mut accounts, &message.transaction).await;
-                    if let Err(err )= message.sender.send(result) {
-                        eprintln!("Cannot send the transaction process result to the client! : {:?}", err);
-                    }
+    let mut outcomes = Vec::new();
+    let mut processed = 0;
+    let mut stopping = false;
+    loop {
+        // On shutdown the channel is closed: the parser stops reading and what is already queued is still answered.
+        // Shed rows come on their own channel, already rejected, and are answered until the parser is done.
+        let message = tokio::select! {
+            biased;
+            Some(()) = signals.recv() => {
+                if stopping {
+                    input.in_scope(|| warn!(queued = rx.len(), "second shutdown signal, abandoning queued transactions"));
+                    return Err(EngineError::Interrupted)
                 }
+                input.in_scope(|| warn!(queued = rx.len(), "shutdown requested, draining queued transactions"));
+                stopping = true;
+                rx.close();
+                continue
+            },
+            Some(message) = shed.recv() => message,
+            message = rx.recv() => match message {
+                Some(message) => message,
+                None => match shed.recv().await {
+                    Some(message) => message,
+                    None => break,
+                },
+            },
+        };
+        let transaction = &message.transaction;
+        let span = info_span!(parent: &input, "transaction", row = transaction.sequence, client = ids::field(transaction.client), tx = ids::field(transaction.tx), r#type = ?transaction.trans_type);
+        let started = Instant::now();
+        let result = match message.rejection {
+            Some(err) => Err(err),
+            None => manager.process_transaction(transaction).instrument(span.clone()).await,
+        };
+        if let Some(metrics) = &metrics {
+            if let Ok(mut metrics) = metrics.lock() {
+                metrics.record(transaction.trans_type, &result, started.elapsed());
+                metrics.set_accounts(manager.client_account_count(), manager.locked_accounts());
+                metrics.set_queue_depth(rx.len());
+            }
+        }
+        span.in_scope(|| debug!(result = ?result, "transaction processed"));
+        processed += 1;
+        if record_outcomes {
+            outcomes.push(replay::Outcome::new(&message.transaction, &result));
+        }
+        if let Err(err )= message.sender.send(result) {
+            warn!(result = ?err, "cannot send the transaction process result to the client");
+        }
+    }
+    input.in_scope(|| info!(processed, interrupted = stopping, "input processed"));
+    Ok((manager, outcomes))
+}
+
+// Sends one message per SIGINT or, on unix, SIGTERM for as long as the receiver is kept.
+fn shutdown_signals() -> UnboundedReceiver<()> {
+    let (tx, rx) = unbounded_channel();
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => Some(terminate),
+            Err(err) => {
+                warn!(error = %err, "cannot listen for SIGTERM");
+                None
+            }
+        };
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => if let Err(err) = result {
+                    warn!(error = %err, "cannot listen for SIGINT");
+                    break
+                },
+                Some(_) = async { terminate.as_mut()?.recv().await } => {},
+            }
+            #[cfg(not(unix))]
+            if tokio::signal::ctrl_c().await.is_err() {
+                break
+            }
+            if tx.send(()).is_err() {
+                break
+            }
+        }
+    });
+    rx
+}
+
+// Differences go to stdout as CSV and make the process exit with 1, so the diff can gate a release.
+async fn replay(options: options::ReplayOptions) -> Result<(), EngineError> {
+    let differences = replay_differences(&options).await?;
+
+    let mut serializer = AsyncWriterBuilder::new()
+        .delimiter(b',')
+        .create_serializer(io::stdout());
+    for difference in &differences {
+        if serializer.serialize(difference).await.is_err(){
+            error!("unable to serialize record");
+        }
+    }
+    if let Err(err) = serializer.flush().await {
+        error!(error = %err, "cannot write output");
+    }
+    if !differences.is_empty() {
+        warn!(differences = differences.len(), "replay found differences");
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Both sides compare the accounts of the report, the house account is not part of a saved report.
+async fn replay_differences(options: &options::ReplayOptions) -> Result<Vec<replay::Difference>, EngineError> {
+    let (base, base_outcomes) = run(&options.base, true, None).await?;
+    let base_accounts: Vec<events::AccountState> = base.client_accounts().map(transaction_manager::Account::state).collect();
+
+    let (candidate_accounts, candidate_outcomes) = match &options.candidate {
+        Some(candidate) => {
+            let (manager, outcomes) = run(candidate, true, None).await?;
+            (manager.client_accounts().map(transaction_manager::Account::state).collect(), Some(outcomes))
+        },
+        None => {
+            let accounts = load_saved(options.saved_accounts.as_deref()).await?.unwrap_or_default();
+            (accounts, load_saved(options.saved_outcomes.as_deref()).await?)
+        },
+    };
+
+    let mut differences = replay::diff_accounts(&base_accounts, &candidate_accounts);
+    if let Some(candidate_outcomes) = candidate_outcomes {
+        differences.extend(replay::diff_outcomes(&base_outcomes, &candidate_outcomes));
+    }
+    Ok(differences)
+}
+
+async fn load_saved<T>(path: Option<&str>) -> Result<Option<Vec<T>>, EngineError>
+    where T: for<'de> serde::Deserialize<'de> + 'static
+{
+    match path {
+        Some(path) => {
+            let file = File::open(path).await.map_err(|err| EngineError::open(path, err))?;
+            csv_parser::deserialize_records(file).await.map(Some).map_err(|err| EngineError::parse(path, err))
+        },
+        None => Ok(None),
+    }
+}
 
-                let mut serializer = AsyncWriterBuilder::new()
-                    .delimiter(b',')
-                    .create_serializer(io::stdout());
+async fn query_as_of(options: options::AsOfOptions) -> Result<(), EngineError> {
+    let file = File::open(&options.history).await.map_err(|err| EngineError::open(&options.history, err))?;
+    let history = history::History::load(file).await.map_err(|err| EngineError::parse(&options.history, err))?;
 
-                for account in accounts {
-                    if serializer.serialize(account.1).await.is_err(){
-                        eprintln!("Unable to deserialize record.");
-                    }
+    let clients = match options.client {
+        Some(client) => vec![client],
+        None => history.clients(),
+    };
+
+    let mut serializer = AsyncWriterBuilder::new()
+        .delimiter(b',')
+        .create_serializer(io::stdout());
+    for client in clients {
+        match history.as_of(client, options.as_of) {
+            Some(point) => {
+                if serializer.serialize(point).await.is_err(){
+                    error!("unable to serialize record");
                 }
             },
-            Err(err) => {eprintln!("Cannot open input file {:?}", err); }
+            None => info!(client = ids::field(client), as_of = ?options.as_of, "no activity"),
+        }
+    }
+    if let Err(err) = serializer.flush().await {
+        error!(error = %err, "cannot write output");
+    }
+    Ok(())
+}
+
+async fn rebuild(options: options::RebuildOptions) -> Result<(), EngineError> {
+    let file = File::open(&options.events).await.map_err(|err| EngineError::open(&options.events, err))?;
+    let stream = events::load(file, options.format).await.map_err(|err| EngineError::parse(&options.events, err))?;
+
+    let mut serializer = AsyncWriterBuilder::new()
+        .delimiter(b',')
+        .create_serializer(io::stdout());
+    for state in events::rebuild(&stream) {
+        if serializer.serialize(state).await.is_err(){
+            error!("unable to serialize record");
+        }
+    }
+    if let Err(err) = serializer.flush().await {
+        error!(error = %err, "cannot write output");
+    }
+    Ok(())
+}
+
+fn print_config(options: &options::Options) -> Result<(), EngineError> {
+    let config = config::Config::effective(options).to_toml().map_err(|err| EngineError::write("stdout", err))?;
+    print!("{}", config);
+    Ok(())
+}
+
+async fn generate_csv(options: options::GenerateOptions) -> Result<(), EngineError> {
+    let generator = generate::Generator::new(options.generator);
+    match &options.output {
+        Some(output) => {
+            let file = File::create(output).await.map_err(|err| EngineError::open(output, err))?;
+            generate::write(file, generator).await.map_err(|err| EngineError::write(output, err))
+        },
+        None => generate::write(io::stdout(), generator).await.map_err(|err| EngineError::write("stdout", err)),
+    }
+}
+
+async fn load_fees(options: &options::Options) -> Result<fee::FeeSchedule, EngineError> {
+    match &options.fees {
+        Some(fees_file) => {
+            let file = File::open(fees_file).await.map_err(|err| EngineError::open(fees_file, err))?;
+            fee::FeeSchedule::load(options.house_account, file).await.map_err(|err| EngineError::parse(fees_file, err))
+        },
+        None => Ok(fee::FeeSchedule::empty()),
+    }
+}
+
+async fn load_risk_controls(options: &options::Options) -> Result<limits::RiskControls, EngineError> {
+    match &options.limits {
+        Some(limits_file) => {
+            let limits = File::open(limits_file).await.map_err(|err| EngineError::open(limits_file, err))?;
+            let tiers = match &options.tiers {
+                Some(tiers_file) => Some(File::open(tiers_file).await.map_err(|err| EngineError::open(tiers_file, err))?),
+                None => None,
+            };
+            limits::RiskControls::load(limits, tiers).await.map_err(|err| EngineError::parse(limits_file, err))
+        },
+        None => Ok(limits::RiskControls::default()),
+    }
+}
+
+async fn load_directory(options: &options::Options) -> Result<directory::Directory, EngineError> {
+    match &options.accounts {
+        Some(accounts_file) => {
+            let file = File::open(accounts_file).await.map_err(|err| EngineError::open(accounts_file, err))?;
+            directory::Directory::load(file, options.unknown_clients).await.map_err(|err| EngineError::parse(accounts_file, err))
+        },
+        None => Ok(directory::Directory::new(Vec::new(), options.unknown_clients)),
+    }
+}
+
+async fn load_rules(options: &options::Options) -> Result<Vec<Box<dyn rules::Rule>>, EngineError> {
+    match &options.rules {
+        Some(rules_file) => {
+            let file = File::open(rules_file).await.map_err(|err| EngineError::open(rules_file, err))?;
+            rules::load(file).await.map_err(|err| EngineError::parse(rules_file, err))
+        },
+        None => Ok(Vec::new()),
+    }
+}
+
+async fn write_records<T: serde::Serialize>(path: &str, records: impl Iterator<Item = T>) {
+    match File::create(path).await {
+        Ok(file) => {
+            let mut serializer = AsyncWriterBuilder::new()
+                .delimiter(b',')
+                .create_serializer(file);
+
+            for record in records {
+                if serializer.serialize(record).await.is_err(){
+                    error!("unable to serialize record");
+                }
+            }
+            if let Err(err) = serializer.flush().await {
+                error!(file = %path, error = %err, "cannot write output file");
+            }
+        },
+        Err(err) => { error!(file = %path, error = %err, "cannot create output file"); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use proptest::prelude::*;
+    use std::fmt::Write;
+
+    const CONFIG: [&str; 9] = ["--fees", "test/fees.csv", "--limits", "test/limits.csv", "--tiers", "test/tiers.csv", "--rules", "test/rules.csv", "--audit"];
+
+    async fn run_file(input: &str, extra: &[&str]) -> Vec<replay::Outcome> {
+        let mut args = vec![input.to_owned()];
+        args.extend(CONFIG.iter().chain(extra).map(|arg| arg.to_string()));
+        let options = options::parse(&args).await.unwrap();
+        let (manager, outcomes) = run(&options, true, None).await.unwrap();
+        assert!(manager.ledger().trial_balance().is_finite());
+        outcomes
+    }
+
+    // Every row of an odd or broken file is either processed or reported, none of them panics the engine.
+    #[tokio::test]
+    async fn test_malformed_input_does_not_panic() {
+        for policy in ["allow", "reject", "cap"].iter() {
+            let outcomes = run_file("test/malformed.csv", &["--dispute-policy", policy, "--reorder-buffer", "3", "--late-policy", "reject"]).await;
+            assert!(outcomes.len() > 20);
+        }
+    }
+
+    // Rows shed by a full queue are rejected as QueueFull, every row still has an outcome.
+    #[tokio::test]
+    async fn test_shed_rows_have_outcomes() {
+        let outcomes = run_file("test/cases/basic/input.csv", &["--queue-depth", "1", "--admission", "shed"]).await;
+        let rows = tokio::fs::read_to_string("test/cases/basic/input.csv").await.unwrap().lines().skip(1).filter(|line| !line.trim().is_empty()).count();
+        assert_eq!(outcomes.len(), rows);
+    }
+
+    // A run with fees replayed against its own report and outcomes finds no difference.
+    #[tokio::test]
+    async fn test_replay_against_own_output_with_fees() {
+        let input = "test/cases/transfers_and_fees/input.csv";
+        let fees = ["--fees", "test/fees.csv", "--house-account", "999"];
+        let mut args = vec![input.to_owned()];
+        args.extend(fees.iter().map(|arg| arg.to_string()));
+        let (manager, outcomes) = run(&options::parse(&args).await.unwrap(), true, None).await.unwrap();
+        assert!(manager.accounts().len() > manager.client_accounts().count());
+
+        let dir = env::temp_dir();
+        let accounts = dir.join(format!("toy_engine_replay_accounts_{}.csv", std::process::id()));
+        let saved_outcomes = dir.join(format!("toy_engine_replay_outcomes_{}.csv", std::process::id()));
+        let mut report = Vec::new();
+        write_accounts(&mut report, b',', manager.client_accounts(), false).await.unwrap();
+        tokio::fs::write(&accounts, report).await.unwrap();
+        tokio::fs::write(&saved_outcomes, render(outcomes.iter()).await).await.unwrap();
+
+        args.extend(["--saved-accounts", accounts.to_str().unwrap(), "--saved-outcomes", saved_outcomes.to_str().unwrap()].iter().map(|arg| arg.to_string()));
+        let differences = replay_differences(&options::parse_replay(&args).await.unwrap()).await.unwrap();
+        tokio::fs::remove_file(&accounts).await.unwrap();
+        tokio::fs::remove_file(&saved_outcomes).await.unwrap();
+        assert!(differences.is_empty(), "{:?}", differences);
+    }
+
+    // The first signal stops the input and answers what is queued, the second one abandons the run.
+    #[tokio::test]
+    async fn test_second_shutdown_signal_interrupts() {
+        let options = options::parse(&["test/malformed.csv".to_owned(), "--queue-depth".to_owned(), "1".to_owned()]).await.unwrap();
+        let rows = run(&options, true, None).await.unwrap().1.len();
+
+        let (tx, signals) = unbounded_channel();
+        tx.send(()).unwrap();
+        let (_, outcomes) = run_until(&options, true, None, signals).await.unwrap();
+        assert!(outcomes.len() < rows);
+
+        let (tx, signals) = unbounded_channel();
+        tx.send(()).unwrap();
+        tx.send(()).unwrap();
+        assert!(matches!(run_until(&options, true, None, signals).await, Err(EngineError::Interrupted)));
+    }
+
+    // Rows drawn from edge-case values for every column, with arbitrary bytes spliced in.
+    fn generated_row() -> impl Strategy<Value = Vec<u8>> {
+        let column = |values: &'static [&'static str]| proptest::sample::select(values);
+        let row = (
+            column(&["deposit", "withdrawal", "dispute", "resolve", "chargeback", "transfer", "reversal", "unknown"]),
+            column(&["1", "2", "3", "65535", "0", ""]),
+            column(&["1", "2", "3", "4", "5", "4294967295", ""]),
+            column(&["1.0", "0.00005", "0", "-1", "1e308", "NaN", "", "x"]),
+            column(&["1", "2", "3", "65535", "0", ""]),
+            column(&["", "0", "5", "1", "18446744073709551615", "2021-08-03T12:00:00Z"]),
+        ).prop_map(|(trans_type, client, tx, amount, destination, timestamp)| {
+            format!("{},{},{},{},{},{}\n", trans_type, client, tx, amount, destination, timestamp).into_bytes()
+        });
+        prop_oneof![9 => row, 1 => proptest::collection::vec(any::<u8>(), 0..32)]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_generated_input_does_not_panic(rows in proptest::collection::vec(generated_row(), 0..200), buffer in 0usize..3) {
+            let mut input = b"type,client,tx,amount,destination,timestamp\n".to_vec();
+            input.extend(rows.concat());
+            let lines = input.split(|byte| *byte == b'\n' || *byte == b'\r').count();
+            let path = env::temp_dir().join(format!("toy_engine_generated_{}.csv", std::process::id()));
+            std::fs::write(&path, input).unwrap();
+
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let outcomes = runtime.block_on(run_file(path.to_str().unwrap(), &["--reorder-buffer", &buffer.to_string()]));
+            std::fs::remove_file(&path).unwrap();
+            prop_assert!(outcomes.len() < lines);
+        }
+    }
+
+    async fn render<T: serde::Serialize>(records: impl Iterator<Item = T>) -> String {
+        let mut serializer = AsyncWriterBuilder::new()
+            .delimiter(b',')
+            .create_serializer(Vec::new());
+        for record in records {
+            serializer.serialize(record).await.unwrap();
+        }
+        String::from_utf8(serializer.into_inner().await.unwrap()).unwrap()
+    }
+
+    fn compare(case: &str, file: &std::path::Path, actual: &str, failures: &mut Vec<String>) {
+        if env::var_os("TOY_ENGINE_BLESS").is_some() {
+            std::fs::write(file, actual).unwrap();
+            return
+        }
+        let expected = std::fs::read_to_string(file).unwrap_or_default();
+        if expected != actual {
+            let mut report = format!("{}: {} differs", case, file.display());
+            for line in expected.lines().filter(|line| !actual.lines().any(|other| other == *line)) {
+                let _ = write!(report, "\n  - {}", line);
+            }
+            for line in actual.lines().filter(|line| !expected.lines().any(|other| other == *line)) {
+                let _ = write!(report, "\n  + {}", line);
+            }
+            failures.push(report);
+        }
+    }
+
+    // Every directory of test/cases is a scenario: input.csv, an optional options file with extra arguments, the expected
+    // accounts.csv sorted by client and the expected rejections.csv. Rows the parser drops (unparsable or late) are only logged
+    // and do not appear in rejections.csv. TOY_ENGINE_BLESS=1 rewrites the expected files.
+    #[tokio::test]
+    async fn test_golden_cases() {
+        let mut cases: Vec<std::path::PathBuf> = std::fs::read_dir("test/cases").unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.join("input.csv").is_file())
+            .collect();
+        cases.sort();
+        assert!(!cases.is_empty());
+
+        let mut failures = Vec::new();
+        for case in &cases {
+            let name = case.file_name().unwrap().to_string_lossy().into_owned();
+            let mut args = vec![case.join("input.csv").to_string_lossy().into_owned()];
+            if let Ok(extra) = std::fs::read_to_string(case.join("options")) {
+                args.extend(extra.split_whitespace().map(str::to_owned));
+            }
+            let options = options::parse(&args).await.unwrap_or_else(|err| panic!("{}: {}", name, err));
+            let (manager, outcomes) = run(&options, true, None).await.unwrap_or_else(|err| panic!("{}: {}", name, err));
+
+            let mut accounts: Vec<&transaction_manager::Account> = manager.client_accounts().collect();
+            accounts.sort_by_key(|account| account.state().client);
+            let mut rendered = Vec::new();
+            write_accounts(&mut rendered, options.output_delimiter, accounts.into_iter(), options.last_activity).await.unwrap();
+            compare(&name, &case.join("accounts.csv"), &String::from_utf8(rendered).unwrap(), &mut failures);
+
+            let rejections = render(outcomes.iter().filter(|outcome| outcome.is_rejection())).await;
+            compare(&name, &case.join("rejections.csv"), &rejections, &mut failures);
         }
-}   else {
-        eprintln!("Input file argument not provided!");
-        eprintln!("Usage parse_csv <source_filepath>");
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
     }
 }
@@ -0,0 +1,212 @@
+use crate::csv_parser::{TransactionError, TransactionStatus, TransactionType};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+const LATENCY_BUCKETS: [f64; 9] = [0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1];
+
+#[derive(Debug)]
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    transactions: BTreeMap<String, u64>,
+    errors: BTreeMap<String, u64>,
+    latency: Histogram,
+    queue_depth: usize,
+    active_accounts: usize,
+    locked_accounts: usize,
+}
+
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            transactions: BTreeMap::new(),
+            errors: BTreeMap::new(),
+            latency: Histogram::new(&LATENCY_BUCKETS),
+            queue_depth: 0,
+            active_accounts: 0,
+            locked_accounts: 0,
+        }
+    }
+}
+
+// Error labels use the variant name only, BlockedByRule("rule") becomes BlockedByRule.
+fn error_label(err: &TransactionError) -> String {
+    let name = format!("{:?}", err);
+    name.split('(').next().unwrap_or_default().to_owned()
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn record(&mut self, trans_type: TransactionType, result: &Result<TransactionStatus, TransactionError>, elapsed: Duration) {
+        *self.transactions.entry(format!("{:?}", trans_type).to_lowercase()).or_default() += 1;
+        if let Err(err) = result {
+            *self.errors.entry(error_label(err)).or_default() += 1;
+        }
+        self.latency.observe(elapsed.as_secs_f64());
+    }
+
+    pub fn set_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth;
+    }
+
+    pub fn set_accounts(&mut self, active: usize, locked: usize) {
+        self.active_accounts = active;
+        self.locked_accounts = locked;
+    }
+
+    // Prometheus text exposition format, version 0.0.4.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP toy_engine_transactions_total Processed transactions by type.");
+        let _ = writeln!(out, "# TYPE toy_engine_transactions_total counter");
+        for (trans_type, count) in &self.transactions {
+            let _ = writeln!(out, "toy_engine_transactions_total{{type=\"{}\"}} {}", trans_type, count);
+        }
+        let _ = writeln!(out, "# HELP toy_engine_transaction_errors_total Rejected transactions by error.");
+        let _ = writeln!(out, "# TYPE toy_engine_transaction_errors_total counter");
+        for (error, count) in &self.errors {
+            let _ = writeln!(out, "toy_engine_transaction_errors_total{{error=\"{}\"}} {}", error, count);
+        }
+        let _ = writeln!(out, "# HELP toy_engine_process_transaction_seconds Time spent in process_transaction.");
+        let _ = writeln!(out, "# TYPE toy_engine_process_transaction_seconds histogram");
+        for (bound, count) in self.latency.bounds.iter().zip(&self.latency.counts) {
+            let _ = writeln!(out, "toy_engine_process_transaction_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+        }
+        let _ = writeln!(out, "toy_engine_process_transaction_seconds_bucket{{le=\"+Inf\"}} {}", self.latency.count);
+        let _ = writeln!(out, "toy_engine_process_transaction_seconds_sum {}", self.latency.sum);
+        let _ = writeln!(out, "toy_engine_process_transaction_seconds_count {}", self.latency.count);
+        let gauges = [
+            ("toy_engine_queue_depth", "Transactions waiting in the pipeline channel.", self.queue_depth),
+            ("toy_engine_active_accounts", "Accounts known to the engine.", self.active_accounts),
+            ("toy_engine_locked_accounts", "Accounts locked by a chargeback.", self.locked_accounts),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+// Reads up to the end of the request line, all the routing needs.
+async fn request_line(socket: &mut TcpStream) -> Option<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(2).any(|end| end == b"\r\n") {
+        let read = socket.read(&mut buffer).await.ok()?;
+        if read == 0 || request.len() > 8192 {
+            return None
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+    String::from_utf8_lossy(&request).lines().next().map(str::to_owned)
+}
+
+// Answers `GET /metrics` with the current metrics and any other request with 404.
+pub async fn serve(listener: TcpListener, metrics: SharedMetrics) {
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => { warn!(error = %err, "cannot accept metrics connection"); continue }
+        };
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let line = request_line(&mut socket).await.unwrap_or_default();
+            let mut parts = line.split_whitespace();
+            let (status, body) = match (parts.next(), parts.next()) {
+                (Some("GET"), Some("/metrics")) => ("200 OK", metrics.lock().map(|metrics| metrics.render()).unwrap_or_default()),
+                _ => ("404 Not Found", "Not found, metrics are served on GET /metrics\n".to_owned()),
+            };
+            let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+            if let Err(err) = socket.write_all(response.as_bytes()).await {
+                warn!(error = %err, "cannot write metrics response");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::*;
+
+    #[test]
+    fn test_render() {
+        let mut metrics = Metrics::new();
+        metrics.record(TransactionType::Deposit, &Ok(TransactionStatus::Applied), Duration::from_micros(20));
+        metrics.record(TransactionType::WithDrawal, &Err(TransactionError::InsufficientFund), Duration::from_millis(2));
+        metrics.record(TransactionType::WithDrawal, &Err(TransactionError::BlockedByRule("rapid_disputes")), Duration::from_secs(1));
+        metrics.set_queue_depth(3);
+        metrics.set_accounts(2, 1);
+
+        let text = metrics.render();
+        assert!(text.contains("toy_engine_transactions_total{type=\"deposit\"} 1\n"));
+        assert!(text.contains("toy_engine_transactions_total{type=\"withdrawal\"} 2\n"));
+        assert!(text.contains("toy_engine_transaction_errors_total{error=\"InsufficientFund\"} 1\n"));
+        assert!(text.contains("toy_engine_transaction_errors_total{error=\"BlockedByRule\"} 1\n"));
+        assert!(text.contains("toy_engine_process_transaction_seconds_bucket{le=\"0.00005\"} 1\n"));
+        assert!(text.contains("toy_engine_process_transaction_seconds_bucket{le=\"0.005\"} 2\n"));
+        assert!(text.contains("toy_engine_process_transaction_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("toy_engine_process_transaction_seconds_count 3\n"));
+        assert!(text.contains("toy_engine_queue_depth 3\n"));
+        assert!(text.contains("toy_engine_active_accounts 2\n"));
+        assert!(text.contains("toy_engine_locked_accounts 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Mutex::new(Metrics::new()));
+        metrics.lock().unwrap().set_accounts(4, 0);
+        tokio::spawn(serve(listener, metrics));
+
+        let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        socket.write_all(b"GET /metrics HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("toy_engine_active_accounts 4\n"));
+
+        for request in [&b"GET / HTTP/1.1\r\n\r\n"[..], b"POST /metrics HTTP/1.1\r\n\r\n", b"GET /metrics"] {
+            let mut socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            socket.write_all(request).await.unwrap();
+            socket.shutdown().await.unwrap();
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+        }
+    }
+}
@@ -0,0 +1,635 @@
+use crate::fee::DEFAULT_HOUSE_ACCOUNT;
+use crate::ids::{self, ClientId};
+use crate::config::Config;
+use crate::csv_parser::{Admission, EventOrdering, InputFormat, MAX_PRECISION, parse_timestamp};
+use std::time::Duration;
+use crate::directory::UnknownClientPolicy;
+use crate::events::EventFormat;
+use crate::generate::GeneratorOptions;
+use crate::history::AsOf;
+use crate::logging::LogOptions;
+use crate::transaction_manager::{DisputePolicy, LockPolicy};
+
+#[derive(Debug)]
+pub struct Options {
+    pub input: String,
+    pub fees: Option<String>,
+    pub house_account: ClientId,
+    pub fee_ledger: Option<String>,
+    pub ledger: Option<String>,
+    pub audit: bool,
+    pub dispute_policy: DisputePolicy,
+    pub limits: Option<String>,
+    pub tiers: Option<String>,
+    pub rules: Option<String>,
+    pub alerts: Option<String>,
+    pub ordering: EventOrdering,
+    pub last_activity: bool,
+    pub history: Option<String>,
+    pub events: Option<String>,
+    pub events_format: EventFormat,
+    pub outcomes: Option<String>,
+    pub metrics_addr: Option<String>,
+    pub metrics_file: Option<String>,
+    pub queue_depth: usize,
+    pub admission: Admission,
+    pub input_format: InputFormat,
+    pub output_delimiter: u8,
+    pub lock_policy: LockPolicy,
+    pub accounts: Option<String>,
+    pub unknown_clients: UnknownClientPolicy,
+}
+
+pub const DEFAULT_QUEUE_DEPTH: usize = 100;
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            input: String::new(),
+            fees: None,
+            house_account: DEFAULT_HOUSE_ACCOUNT,
+            fee_ledger: None,
+            ledger: None,
+            audit: false,
+            dispute_policy: DisputePolicy::AllowNegative,
+            limits: None,
+            tiers: None,
+            rules: None,
+            alerts: None,
+            ordering: EventOrdering::default(),
+            last_activity: false,
+            history: None,
+            events: None,
+            events_format: EventFormat::Csv,
+            outcomes: None,
+            metrics_addr: None,
+            metrics_file: None,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            admission: Admission::default(),
+            input_format: InputFormat::default(),
+            output_delimiter: b',',
+            lock_policy: LockPolicy::Transfers,
+            accounts: None,
+            unknown_clients: UnknownClientPolicy::Create,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AsOfOptions {
+    pub history: String,
+    pub client: Option<ClientId>,
+    pub as_of: AsOf,
+}
+
+#[derive(Debug)]
+pub struct RebuildOptions {
+    pub events: String,
+    pub format: EventFormat,
+}
+
+// The candidate run takes the input file of the base run and only its own options.
+#[derive(Debug)]
+pub struct ReplayOptions {
+    pub base: Box<Options>,
+    pub candidate: Option<Box<Options>>,
+    pub saved_accounts: Option<String>,
+    pub saved_outcomes: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct GenerateOptions {
+    pub output: Option<String>,
+    pub generator: GeneratorOptions,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Process(Box<Options>),
+    AsOf(AsOfOptions),
+    Rebuild(RebuildOptions),
+    Replay(ReplayOptions),
+    Generate(GenerateOptions),
+    PrintConfig(Box<Options>),
+}
+
+pub const USAGE: &str = "Usage parse_csv <source_filepath> [--fees <fee_schedule_filepath>] [--house-account <client_id>] [--fee-ledger <output_filepath>] [--ledger <output_filepath>] [--audit] [--dispute-policy <allow|reject|cap>] [--limits <tier_limits_filepath>] [--tiers <client_tiers_filepath>] [--rules <rules_filepath>] [--alerts <output_filepath>] [--reorder-buffer <size>] [--late-policy <accept|drop|reject>] [--last-activity] [--history <output_filepath>] [--events <output_filepath>] [--events-format <csv|jsonl>] [--outcomes <output_filepath>] [--metrics-addr <host:port>] [--metrics-file <output_filepath>] [--queue-depth <size>] [--admission <block|shed>] [--result-timeout <millis>] [--config <config_filepath>] [--delimiter <char>] [--precision <decimals>] [--rounding <truncate|round>] [--output-delimiter <char>] [--lock-policy <transfers|outgoing|frozen>] [--accounts <accounts_filepath>] [--unknown-clients <create|reject>]
+       parse_csv print-config [--config <config_filepath>] [<source_filepath>] [options]
+       parse_csv as-of <history_filepath> (--tx <tx_id> | --time <timestamp>) [--client <client_id>]
+       parse_csv rebuild <events_filepath> [--format <csv|jsonl>]
+       parse_csv replay <source_filepath> [options] (-- [candidate options] | --saved-accounts <accounts_filepath> [--saved-outcomes <outcomes_filepath>])
+       parse_csv generate [--clients <count>] [--transactions <count>] [--dispute-ratio <0..1>] [--seed <seed>] [--output <output_filepath>]
+Every command also takes [--log-level <level|filter>] [--log-format <text|json>]";
+
+// Logging options apply to every command, they are taken out before the command itself is parsed.
+pub fn parse_logging(args: &[String]) -> Result<(LogOptions, Vec<String>), String> {
+    let mut log = LogOptions::default();
+    let mut rest = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--log-level" => {
+                log.level = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--log-format" => {
+                log.format = value(&mut args, arg)?.parse()?;
+            },
+            _ => rest.push(arg.to_owned()),
+        }
+    }
+    Ok((log, rest))
+}
+
+pub async fn parse_command(args: &[String]) -> Result<Command, String> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("as-of") => parse_as_of(&args[1..]).map(Command::AsOf),
+        Some("rebuild") => parse_rebuild(&args[1..]).map(Command::Rebuild),
+        Some("replay") => parse_replay(&args[1..]).await.map(Command::Replay),
+        Some("generate") => parse_generate(&args[1..]).map(Command::Generate),
+        Some("print-config") => parse_options(&args[1..]).await.map(|options| Command::PrintConfig(Box::new(options))),
+        _ => parse(args).await.map(|options| Command::Process(Box::new(options))),
+    }
+}
+
+pub fn parse_as_of(args: &[String]) -> Result<AsOfOptions, String> {
+    let mut history = None;
+    let mut client = None;
+    let mut as_of = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--client" => {
+                let id = value(&mut args, arg)?;
+                client = Some(id.parse().map_err(|_| format!("Invalid client id for {}: {}", arg, id))?);
+            },
+            "--tx" => {
+                let tx = value(&mut args, arg)?;
+                as_of = Some(AsOf::Tx(tx.parse().map_err(|_| format!("Invalid tx id for {}: {}", arg, tx))?));
+            },
+            "--time" => {
+                as_of = Some(AsOf::Time(parse_timestamp(value(&mut args, arg)?)?));
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if history.is_none() => history = Some(arg.to_owned()),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    match (history, as_of) {
+        (Some(history), Some(as_of)) => Ok(AsOfOptions { history, client, as_of }),
+        (None, _) => Err("History file argument not provided!".to_owned()),
+        (_, None) => Err("Either --tx or --time is required".to_owned()),
+    }
+}
+
+pub fn parse_rebuild(args: &[String]) -> Result<RebuildOptions, String> {
+    let mut events = None;
+    let mut format = EventFormat::Csv;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                format = value(&mut args, arg)?.parse()?;
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if events.is_none() => events = Some(arg.to_owned()),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    match events {
+        Some(events) => Ok(RebuildOptions { events, format }),
+        None => Err("Events file argument not provided!".to_owned()),
+    }
+}
+
+pub async fn parse_replay(args: &[String]) -> Result<ReplayOptions, String> {
+    let (base_args, candidate_args) = match args.iter().position(|arg| arg == "--") {
+        Some(separator) => (&args[..separator], Some(&args[separator + 1..])),
+        None => (args, None),
+    };
+
+    let mut saved_accounts = None;
+    let mut saved_outcomes = None;
+    let mut rest = Vec::new();
+    let mut base_args = base_args.iter();
+    while let Some(arg) = base_args.next() {
+        match arg.as_str() {
+            "--saved-accounts" => {
+                saved_accounts = Some(value(&mut base_args, arg)?.to_owned());
+            },
+            "--saved-outcomes" => {
+                saved_outcomes = Some(value(&mut base_args, arg)?.to_owned());
+            },
+            _ => rest.push(arg.to_owned()),
+        }
+    }
+    let base = parse(&rest).await?;
+
+    let candidate = match candidate_args {
+        Some(candidate_args) => {
+            let mut args = vec![base.input.clone()];
+            args.extend(candidate_args.iter().cloned());
+            Some(Box::new(parse(&args).await?))
+        },
+        None => None,
+    };
+
+    match (&candidate, &saved_accounts, &saved_outcomes) {
+        (None, None, None) => Err("replay needs candidate options after -- or --saved-accounts".to_owned()),
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => Err("replay compares against either candidate options or a saved output, not both".to_owned()),
+        (None, None, Some(_)) => Err("--saved-outcomes requires --saved-accounts".to_owned()),
+        _ => Ok(ReplayOptions { base: Box::new(base), candidate, saved_accounts, saved_outcomes }),
+    }
+}
+
+pub fn parse_generate(args: &[String]) -> Result<GenerateOptions, String> {
+    let mut output = None;
+    let mut generator = GeneratorOptions::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--clients" => {
+                let count = value(&mut args, arg)?;
+                generator.clients = count.parse().ok().filter(|count| (1..=ids::MAX_CLIENT_NUMBER).contains(count)).ok_or(format!("Invalid count for {}: {}", arg, count))?;
+            },
+            "--transactions" => {
+                let count = value(&mut args, arg)?;
+                generator.transactions = count.parse().ok().filter(|count| (0..=ids::MAX_TX_NUMBER).contains(count)).ok_or(format!("Invalid count for {}: {}", arg, count))?;
+            },
+            "--dispute-ratio" => {
+                let ratio = value(&mut args, arg)?;
+                generator.dispute_ratio = ratio.parse().ok().filter(|ratio| (0.0..=1.0).contains(ratio)).ok_or(format!("Invalid ratio for {}: {}", arg, ratio))?;
+            },
+            "--seed" => {
+                let seed = value(&mut args, arg)?;
+                generator.seed = seed.parse().map_err(|_| format!("Invalid seed for {}: {}", arg, seed))?;
+            },
+            "--output" => {
+                output = Some(value(&mut args, arg)?.to_owned());
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+    Ok(GenerateOptions { output, generator })
+}
+
+pub async fn parse(args: &[String]) -> Result<Options, String> {
+    let options = parse_options(args).await?;
+    if options.input.is_empty() {
+        return Err("Input file argument not provided!".to_owned())
+    }
+    Ok(options)
+}
+
+// The configuration file is read first whatever the position of --config, every other flag overrides it.
+async fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options::default();
+    if let Some(position) = args.iter().position(|arg| arg == "--config") {
+        let path = args.get(position + 1).ok_or("Missing value for --config")?;
+        Config::load(path).await?.apply(&mut options)?;
+    }
+
+    let mut input = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => {
+                value(&mut args, arg)?;
+            },
+            "--fees" => {
+                options.fees = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--house-account" => {
+                let id = value(&mut args, arg)?;
+                options.house_account = id.parse().map_err(|_| format!("Invalid client id for {}: {}", arg, id))?;
+            },
+            "--fee-ledger" => {
+                options.fee_ledger = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--ledger" => {
+                options.ledger = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--audit" => {
+                options.audit = true;
+            },
+            "--dispute-policy" => {
+                options.dispute_policy = value(&mut args, arg)?.parse()?;
+            },
+            "--lock-policy" => {
+                options.lock_policy = value(&mut args, arg)?.parse()?;
+            },
+            "--limits" => {
+                options.limits = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--tiers" => {
+                options.tiers = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--accounts" => {
+                options.accounts = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--unknown-clients" => {
+                options.unknown_clients = value(&mut args, arg)?.parse()?;
+            },
+            "--rules" => {
+                options.rules = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--alerts" => {
+                options.alerts = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--delimiter" => {
+                options.input_format.delimiter = parse_delimiter(value(&mut args, arg)?)?;
+            },
+            "--precision" => {
+                options.input_format.precision = parse_precision(value(&mut args, arg)?)?;
+            },
+            "--rounding" => {
+                options.input_format.rounding = value(&mut args, arg)?.parse()?;
+            },
+            "--output-delimiter" => {
+                options.output_delimiter = parse_delimiter(value(&mut args, arg)?)?;
+            },
+            "--reorder-buffer" => {
+                let size = value(&mut args, arg)?;
+                options.ordering.buffer = size.parse().map_err(|_| format!("Invalid size for {}: {}", arg, size))?;
+            },
+            "--late-policy" => {
+                options.ordering.late_policy = value(&mut args, arg)?.parse()?;
+            },
+            "--last-activity" => {
+                options.last_activity = true;
+            },
+            "--history" => {
+                options.history = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--events" => {
+                options.events = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--events-format" => {
+                options.events_format = value(&mut args, arg)?.parse()?;
+            },
+            "--outcomes" => {
+                options.outcomes = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--metrics-addr" => {
+                options.metrics_addr = Some(parse_addr(value(&mut args, arg)?)?);
+            },
+            "--metrics-file" => {
+                options.metrics_file = Some(value(&mut args, arg)?.to_owned());
+            },
+            "--queue-depth" => {
+                options.queue_depth = parse_queue_depth(value(&mut args, arg)?)?;
+            },
+            "--admission" => {
+                options.admission.policy = value(&mut args, arg)?.parse()?;
+            },
+            "--result-timeout" => {
+                let millis = value(&mut args, arg)?;
+                options.admission.result_timeout = Some(Duration::from_millis(millis.parse().map_err(|_| format!("Invalid timeout for {}: {}", arg, millis))?));
+            },
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ if input.is_none() => input = Some(arg.to_owned()),
+            _ => return Err(format!("Unexpected argument {}", arg)),
+        }
+    }
+
+    if options.tiers.is_some() && options.limits.is_none() {
+        return Err("--tiers ([limits] tiers) requires --limits ([limits] file)".to_owned())
+    }
+    if options.unknown_clients == UnknownClientPolicy::Reject && options.accounts.is_none() {
+        return Err("--unknown-clients reject ([accounts] unknown_clients) requires --accounts ([accounts] file)".to_owned())
+    }
+    if let Some(input) = input {
+        options.input = input;
+    }
+    Ok(options)
+}
+
+pub fn parse_delimiter(delimiter: &str) -> Result<u8, String> {
+    match delimiter.as_bytes() {
+        [byte] if byte.is_ascii() && !matches!(byte, b'"' | b'\n' | b'\r') => Ok(*byte),
+        _ => Err(format!("Invalid delimiter {:?}, expected a single ASCII character other than a quote or a line break", delimiter)),
+    }
+}
+
+pub fn parse_precision(precision: &str) -> Result<u32, String> {
+    precision.parse().ok().filter(|precision| *precision <= MAX_PRECISION)
+        .ok_or(format!("Invalid precision {}, expected 0 to {} decimals", precision, MAX_PRECISION))
+}
+
+pub fn parse_queue_depth(size: &str) -> Result<usize, String> {
+    size.parse().ok().filter(|size| *size > 0).ok_or(format!("Invalid queue depth {}, expected a positive size", size))
+}
+
+pub fn parse_addr(addr: &str) -> Result<String, String> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(addr.to_owned()),
+        _ => Err(format!("Invalid address {}, expected host:port", addr)),
+    }
+}
+
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, option: &str) -> Result<&'a str, String> {
+    args.next().map(|value| value.as_str()).ok_or(format!("Missing value for {}", option))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::*;
+    use crate::csv_parser::{AdmissionPolicy, LatePolicy};
+    use crate::ids::{client_id, tx_id};
+    use crate::logging::LogFormat;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_parse_input_only() {
+        let options = parse(&args(&["transactions.csv"])).await.unwrap();
+
+        assert_eq!(options.input, "transactions.csv");
+        assert_eq!(options.fees, None);
+        assert_eq!(options.house_account, DEFAULT_HOUSE_ACCOUNT);
+        assert_eq!(options.fee_ledger, None);
+        assert_eq!(options.ledger, None);
+        assert!(!options.audit);
+        assert_eq!(options.dispute_policy, DisputePolicy::AllowNegative);
+        assert_eq!(options.limits, None);
+        assert_eq!(options.tiers, None);
+        assert_eq!(options.accounts, None);
+        assert_eq!(options.unknown_clients, UnknownClientPolicy::Create);
+        assert_eq!(options.rules, None);
+        assert_eq!(options.alerts, None);
+        assert_eq!(options.ordering.buffer, 0);
+        assert_eq!(options.ordering.late_policy, LatePolicy::Accept);
+        assert!(!options.last_activity);
+        assert_eq!(options.history, None);
+        assert_eq!(options.events, None);
+        assert_eq!(options.events_format, EventFormat::Csv);
+        assert_eq!(options.outcomes, None);
+        assert_eq!(options.metrics_addr, None);
+        assert_eq!(options.metrics_file, None);
+        assert_eq!(options.queue_depth, DEFAULT_QUEUE_DEPTH);
+        assert_eq!(options.admission, Admission::default());
+    }
+
+    #[tokio::test]
+    async fn test_parse_all_options() {
+        let options = parse(&args(&["--fees", "fees.csv", "transactions.csv", "--house-account", "9",
+            "--fee-ledger", "fee_ledger.csv", "--ledger", "ledger.csv", "--audit", "--dispute-policy", "cap",
+            "--limits", "limits.csv", "--tiers", "tiers.csv", "--accounts", "accounts.csv", "--unknown-clients", "reject",
+            "--rules", "rules.csv", "--alerts", "alerts.csv",
+            "--reorder-buffer", "16", "--late-policy", "drop", "--last-activity",
+            "--history", "history.csv", "--events", "events.jsonl", "--events-format", "jsonl",
+            "--outcomes", "outcomes.csv", "--metrics-addr", "127.0.0.1:9898", "--metrics-file", "metrics.prom",
+            "--queue-depth", "8", "--admission", "shed", "--result-timeout", "250"])).await.unwrap();
+
+        assert_eq!(options.input, "transactions.csv");
+        assert_eq!(options.fees.as_deref(), Some("fees.csv"));
+        assert_eq!(options.house_account, client_id(9));
+        assert_eq!(options.fee_ledger.as_deref(), Some("fee_ledger.csv"));
+        assert_eq!(options.ledger.as_deref(), Some("ledger.csv"));
+        assert!(options.audit);
+        assert_eq!(options.dispute_policy, DisputePolicy::Cap);
+        assert_eq!(options.limits.as_deref(), Some("limits.csv"));
+        assert_eq!(options.tiers.as_deref(), Some("tiers.csv"));
+        assert_eq!(options.accounts.as_deref(), Some("accounts.csv"));
+        assert_eq!(options.unknown_clients, UnknownClientPolicy::Reject);
+        assert_eq!(options.rules.as_deref(), Some("rules.csv"));
+        assert_eq!(options.alerts.as_deref(), Some("alerts.csv"));
+        assert_eq!(options.ordering.buffer, 16);
+        assert_eq!(options.ordering.late_policy, LatePolicy::Drop);
+        assert!(options.last_activity);
+        assert_eq!(options.history.as_deref(), Some("history.csv"));
+        assert_eq!(options.events.as_deref(), Some("events.jsonl"));
+        assert_eq!(options.events_format, EventFormat::Jsonl);
+        assert_eq!(options.outcomes.as_deref(), Some("outcomes.csv"));
+        assert_eq!(options.metrics_addr.as_deref(), Some("127.0.0.1:9898"));
+        assert_eq!(options.metrics_file.as_deref(), Some("metrics.prom"));
+        assert_eq!(options.queue_depth, 8);
+        assert_eq!(options.admission.policy, AdmissionPolicy::Shed);
+        assert_eq!(options.admission.result_timeout, Some(Duration::from_millis(250)));
+    }
+
+    #[tokio::test]
+    async fn test_parse_errors() {
+        assert!(parse(&args(&[])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--fees"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--house-account", ""])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--unknown"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--dispute-policy", "never"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--tiers", "tiers.csv"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--unknown-clients", "reject"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--accounts", "accounts.csv", "--unknown-clients", "ignore"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--reorder-buffer", "-1"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--late-policy", "later"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--events-format", "xml"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--queue-depth", "0"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--admission", "drop"])).await.is_err());
+        assert!(parse(&args(&["transactions.csv", "--result-timeout", "1s"])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_command() {
+        assert!(matches!(parse_command(&args(&["transactions.csv"])).await, Ok(Command::Process(_))));
+
+        match parse_command(&args(&["as-of", "history.csv", "--client", "2", "--tx", "7"])).await {
+            Ok(Command::AsOf(options)) => {
+                assert_eq!(options.history, "history.csv");
+                assert_eq!(options.client, Some(client_id(2)));
+                assert_eq!(options.as_of, AsOf::Tx(tx_id(7)));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match parse_command(&args(&["as-of", "history.csv", "--time", "2021-08-03T12:00:01Z"])).await {
+            Ok(Command::AsOf(options)) => {
+                assert_eq!(options.client, None);
+                assert_eq!(options.as_of, AsOf::Time(1627992001000));
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        assert!(parse_command(&args(&["as-of", "history.csv"])).await.is_err());
+        assert!(parse_command(&args(&["as-of", "--tx", "1"])).await.is_err());
+        assert!(parse_command(&args(&["as-of", "history.csv", "--time", "soon"])).await.is_err());
+
+        match parse_command(&args(&["rebuild", "events.jsonl", "--format", "jsonl"])).await {
+            Ok(Command::Rebuild(options)) => {
+                assert_eq!(options.events, "events.jsonl");
+                assert_eq!(options.format, EventFormat::Jsonl);
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_command(&args(&["rebuild"])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_generate() {
+        match parse_command(&args(&["generate", "--clients", "50", "--transactions", "10000000", "--dispute-ratio", "0.05", "--seed", "3", "--output", "big.csv"])).await {
+            Ok(Command::Generate(options)) => {
+                assert_eq!(options.output.as_deref(), Some("big.csv"));
+                assert_eq!(options.generator, GeneratorOptions { clients: 50, transactions: 10_000_000, dispute_ratio: 0.05, seed: 3 });
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let options = parse_generate(&[]).unwrap();
+        assert_eq!(options.output, None);
+        assert_eq!(options.generator, GeneratorOptions::default());
+
+        assert!(parse_generate(&args(&["--clients", "0"])).is_err());
+        assert!(parse_generate(&args(&["--dispute-ratio", "1.5"])).is_err());
+        assert!(parse_generate(&args(&["--transactions", "-1"])).is_err());
+        assert!(parse_generate(&args(&["--clients", &ids::MAX_CLIENT_NUMBER.to_string(), "--transactions", &ids::MAX_TX_NUMBER.to_string()])).is_ok());
+        if let Some(house) = ids::MAX_CLIENT_NUMBER.checked_add(1) {
+            assert!(parse_generate(&args(&["--clients", &house.to_string()])).is_err());
+        }
+        if let Some(over) = ids::MAX_TX_NUMBER.checked_add(1) {
+            assert!(parse_generate(&args(&["--transactions", &over.to_string()])).is_err());
+        }
+        assert!(parse_generate(&args(&["out.csv"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_parse_replay() {
+        let options = parse_replay(&args(&["transactions.csv", "--fees", "fees.csv", "--", "--dispute-policy", "reject"])).await.unwrap();
+        assert_eq!(options.base.input, "transactions.csv");
+        assert_eq!(options.base.fees.as_deref(), Some("fees.csv"));
+        assert_eq!(options.base.dispute_policy, DisputePolicy::AllowNegative);
+        let candidate = options.candidate.unwrap();
+        assert_eq!(candidate.input, "transactions.csv");
+        assert_eq!(candidate.fees, None);
+        assert_eq!(candidate.dispute_policy, DisputePolicy::Reject);
+
+        let options = parse_replay(&args(&["transactions.csv", "--saved-accounts", "accounts.csv", "--saved-outcomes", "outcomes.csv"])).await.unwrap();
+        assert!(options.candidate.is_none());
+        assert_eq!(options.saved_accounts.as_deref(), Some("accounts.csv"));
+        assert_eq!(options.saved_outcomes.as_deref(), Some("outcomes.csv"));
+
+        assert!(parse_replay(&args(&["transactions.csv"])).await.is_err());
+        assert!(parse_replay(&args(&["transactions.csv", "--saved-outcomes", "outcomes.csv"])).await.is_err());
+        assert!(parse_replay(&args(&["transactions.csv", "--saved-accounts", "accounts.csv", "--"])).await.is_err());
+        assert!(parse_replay(&args(&["transactions.csv", "--", "other.csv"])).await.is_err());
+    }
+
+    #[test]
+    fn test_parse_logging() {
+        let (log, rest) = parse_logging(&args(&["--log-level", "debug", "as-of", "history.csv", "--log-format", "json", "--tx", "1"])).unwrap();
+        assert_eq!(log.level.as_deref(), Some("debug"));
+        assert_eq!(log.format, LogFormat::Json);
+        assert_eq!(rest, args(&["as-of", "history.csv", "--tx", "1"]));
+
+        let (log, rest) = parse_logging(&args(&["transactions.csv"])).unwrap();
+        assert_eq!(log, LogOptions::default());
+        assert_eq!(rest, args(&["transactions.csv"]));
+
+        assert!(parse_logging(&args(&["transactions.csv", "--log-format", "xml"])).is_err());
+        assert!(parse_logging(&args(&["transactions.csv", "--log-level"])).is_err());
+    }
+}
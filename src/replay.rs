@@ -0,0 +1,186 @@
+use crate::csv_parser::{Transaction, TransactionType, TransactionError, TransactionStatus};
+use crate::ids::{ClientId, TxId};
+use crate::events::AccountState;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const ACCEPTED: &str = "accepted";
+const DUPLICATE: &str = "duplicate";
+const REJECTED: &str = "rejected";
+
+// Accept/reject result of one input row, rejections keep the error name.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub row: u64,
+    pub tx: TxId,
+    pub client: ClientId,
+    #[serde(rename = "type")]
+    pub trans_type: TransactionType,
+    pub result: String,
+}
+
+impl Outcome {
+    pub fn new(transaction: &Transaction, result: &Result<TransactionStatus, TransactionError>) -> Outcome {
+        Outcome {
+            row: transaction.sequence,
+            tx: transaction.tx,
+            client: transaction.client,
+            trans_type: transaction.trans_type,
+            result: match result {
+                Ok(TransactionStatus::Applied) => ACCEPTED.to_owned(),
+                Ok(TransactionStatus::Duplicate) => DUPLICATE.to_owned(),
+                Err(err) => format!("{:?}", err),
+            },
+        }
+    }
+
+    fn status(&self) -> &str {
+        match self.result.as_str() {
+            ACCEPTED | DUPLICATE => &self.result,
+            _ => REJECTED,
+        }
+    }
+
+    pub fn is_rejection(&self) -> bool {
+        self.status() == REJECTED
+    }
+}
+
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DifferenceKind {
+    Balance,
+    Outcome,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub kind: DifferenceKind,
+    pub client: ClientId,
+    pub row: Option<u64>,
+    pub tx: Option<TxId>,
+    pub field: String,
+    pub base: String,
+    pub candidate: String,
+}
+
+impl Difference {
+    fn balance(client: ClientId, field: &str, base: String, candidate: String) -> Difference {
+        Difference { kind: DifferenceKind::Balance, client, row: None, tx: None, field: field.to_owned(), base, candidate }
+    }
+}
+
+// A client missing on one side is reported once with its field set to "account".
+pub fn diff_accounts(base: &[AccountState], candidate: &[AccountState]) -> Vec<Difference> {
+    let base: BTreeMap<ClientId, &AccountState> = base.iter().map(|state| (state.client, state)).collect();
+    let candidate: BTreeMap<ClientId, &AccountState> = candidate.iter().map(|state| (state.client, state)).collect();
+    let mut clients: Vec<ClientId> = base.keys().chain(candidate.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let mut differences = Vec::new();
+    for client in clients {
+        match (base.get(&client), candidate.get(&client)) {
+            (Some(base), Some(candidate)) => {
+                let fields = [
+                    ("available", base.available.to_string(), candidate.available.to_string()),
+                    ("held", base.held.to_string(), candidate.held.to_string()),
+                    ("total", base.total.to_string(), candidate.total.to_string()),
+                    ("locked", base.locked.to_string(), candidate.locked.to_string()),
+                    ("overdrawn", base.overdrawn.to_string(), candidate.overdrawn.to_string()),
+                    ("status", base.status.to_string(), candidate.status.to_string()),
+                ];
+                for (field, base, candidate) in fields.iter() {
+                    if base != candidate {
+                        differences.push(Difference::balance(client, field, base.clone(), candidate.clone()));
+                    }
+                }
+            },
+            (Some(_), None) => differences.push(Difference::balance(client, "account", "present".to_owned(), "missing".to_owned())),
+            (None, Some(_)) => differences.push(Difference::balance(client, "account", "missing".to_owned(), "present".to_owned())),
+            (None, None) => {},
+        }
+    }
+    differences
+}
+
+// Rows are matched on their input row number; rows whose accepted, duplicate or rejected status flipped are reported,
+// and a row missing on one side is reported with "missing" as its result there.
+pub fn diff_outcomes(base: &[Outcome], candidate: &[Outcome]) -> Vec<Difference> {
+    let base: BTreeMap<u64, &Outcome> = base.iter().map(|outcome| (outcome.row, outcome)).collect();
+    let candidate: BTreeMap<u64, &Outcome> = candidate.iter().map(|outcome| (outcome.row, outcome)).collect();
+    let mut rows: Vec<u64> = base.keys().chain(candidate.keys()).copied().collect();
+    rows.sort_unstable();
+    rows.dedup();
+
+    let mut differences = Vec::new();
+    for row in rows {
+        let (outcome, base_result, candidate_result) = match (base.get(&row), candidate.get(&row)) {
+            (Some(base), Some(candidate)) if base.status() != candidate.status() => (base, base.result.clone(), candidate.result.clone()),
+            (Some(base), None) => (base, base.result.clone(), "missing".to_owned()),
+            (None, Some(candidate)) => (candidate, "missing".to_owned(), candidate.result.clone()),
+            _ => continue,
+        };
+        differences.push(Difference {
+            kind: DifferenceKind::Outcome,
+            client: outcome.client,
+            row: Some(row),
+            tx: Some(outcome.tx),
+            field: "result".to_owned(),
+            base: base_result,
+            candidate: candidate_result,
+        });
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay::*;
+    use crate::directory::AccountStatus;
+    use crate::ids::{client_id, tx_id};
+
+    fn state(client: u64, available: f64, locked: bool) -> AccountState {
+        AccountState { client: client_id(client), available, held: 0.0, total: available, locked, overdrawn: 0.0, status: AccountStatus::Active }
+    }
+
+    fn outcome(row: u64, result: &str) -> Outcome {
+        Outcome { row, tx: tx_id(row), client: client_id(1), trans_type: TransactionType::WithDrawal, result: result.to_owned() }
+    }
+
+    #[test]
+    fn test_diff_accounts() {
+        let base = vec![state(1, 10.0, false), state(2, 5.0, false), state(3, 1.0, false)];
+        let candidate = vec![state(1, 10.0, false), state(2, 4.5, true), state(4, 1.0, false)];
+
+        let differences = diff_accounts(&base, &candidate);
+        assert_eq!(differences.iter().map(|difference| (difference.client, difference.field.as_str())).collect::<Vec<_>>(),
+            [(2, "available"), (2, "total"), (2, "locked"), (3, "account"), (4, "account")].map(|(client, field)| (client_id(client), field)));
+        assert_eq!(differences[0].base, "5");
+        assert_eq!(differences[0].candidate, "4.5");
+        assert!(diff_accounts(&base, &base).is_empty());
+    }
+
+    #[test]
+    fn test_diff_outcomes() {
+        let base = vec![outcome(1, "accepted"), outcome(2, "InsufficientFund"), outcome(3, "accepted"), outcome(4, "InsufficientFund"), outcome(5, "duplicate")];
+        let candidate = vec![outcome(1, "accepted"), outcome(2, "accepted"), outcome(3, "WithdrawalLimitExceeded"), outcome(4, "AccountLocked"), outcome(5, "ExistingTransactionId")];
+
+        let differences = diff_outcomes(&base, &candidate);
+        assert_eq!(differences.len(), 3);
+        assert_eq!((differences[0].row, differences[0].base.as_str(), differences[0].candidate.as_str()), (Some(2), "InsufficientFund", "accepted"));
+        assert_eq!((differences[1].row, differences[1].base.as_str(), differences[1].candidate.as_str()), (Some(3), "accepted", "WithdrawalLimitExceeded"));
+        assert_eq!((differences[2].row, differences[2].base.as_str(), differences[2].candidate.as_str()), (Some(5), "duplicate", "ExistingTransactionId"));
+    }
+
+    #[test]
+    fn test_diff_outcomes_missing_rows() {
+        let base = vec![outcome(1, "accepted"), outcome(2, "accepted"), outcome(3, "InsufficientFund")];
+        let candidate = vec![outcome(1, "accepted"), outcome(3, "InsufficientFund"), outcome(4, "accepted")];
+
+        let differences = diff_outcomes(&base, &candidate);
+        assert_eq!(differences.len(), 2);
+        assert_eq!((differences[0].row, differences[0].base.as_str(), differences[0].candidate.as_str()), (Some(2), "accepted", "missing"));
+        assert_eq!((differences[1].row, differences[1].base.as_str(), differences[1].candidate.as_str()), (Some(4), "missing", "accepted"));
+    }
+}
@@ -0,0 +1,287 @@
+use crate::csv_parser::{Transaction, TransactionType, deserialize_records};
+use crate::ids::{ClientId, TxId};
+use crate::transaction_manager::Account;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Flag,
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Allow,
+    Flag(String),
+    Block(String),
+}
+
+impl Verdict {
+    fn from_action(action: Action, reason: String) -> Verdict {
+        match action {
+            Action::Flag => Verdict::Flag(reason),
+            Action::Block => Verdict::Block(reason),
+        }
+    }
+}
+
+// Rules see every transaction together with the state of its account before the transaction is applied, and only
+// remember the ones that were applied, as the limits do with `check` and `record`.
+pub trait Rule: Send {
+    fn name(&self) -> &'static str;
+    fn inspect(&self, transaction: &Transaction, account: &Account) -> Verdict;
+    fn record(&mut self, transaction: &Transaction);
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Alert {
+    pub rule: &'static str,
+    pub action: Action,
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub trans_type: TransactionType,
+    pub reason: String,
+}
+
+pub struct RapidDisputes {
+    pub window: u64,
+    pub threshold: usize,
+    pub action: Action,
+    disputes: HashMap<ClientId, VecDeque<u64>>,
+}
+
+impl RapidDisputes {
+    pub fn new(window: u64, threshold: usize, action: Action) -> RapidDisputes {
+        RapidDisputes { window, threshold, action, disputes: HashMap::new() }
+    }
+}
+
+impl Rule for RapidDisputes {
+    fn name(&self) -> &'static str {
+        "rapid_disputes"
+    }
+
+    fn inspect(&self, transaction: &Transaction, _account: &Account) -> Verdict {
+        if transaction.trans_type != TransactionType::Dispute {
+            return Verdict::Allow
+        }
+        let now = transaction.time();
+        let earlier = self.disputes.get(&transaction.client)
+            .map_or(0, |disputes| disputes.iter().filter(|time| time.saturating_add(self.window) > now).count());
+
+        if earlier + 1 >= self.threshold {
+            Verdict::from_action(self.action, format!("{} disputes within {}", earlier + 1, self.window))
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn record(&mut self, transaction: &Transaction) {
+        if transaction.trans_type != TransactionType::Dispute {
+            return
+        }
+        let now = transaction.time();
+        let window = self.window;
+        let disputes = self.disputes.entry(transaction.client).or_default();
+        while disputes.front().is_some_and(|time| time.saturating_add(window) <= now) {
+            disputes.pop_front();
+        }
+        disputes.push_back(now);
+    }
+}
+
+pub struct DepositThenWithdrawal {
+    pub window: u64,
+    pub action: Action,
+    deposits: HashMap<ClientId, u64>,
+}
+
+impl DepositThenWithdrawal {
+    pub fn new(window: u64, action: Action) -> DepositThenWithdrawal {
+        DepositThenWithdrawal { window, action, deposits: HashMap::new() }
+    }
+}
+
+impl Rule for DepositThenWithdrawal {
+    fn name(&self) -> &'static str {
+        "deposit_withdrawal"
+    }
+
+    fn inspect(&self, transaction: &Transaction, _account: &Account) -> Verdict {
+        let now = transaction.time();
+        match transaction.trans_type {
+            TransactionType::WithDrawal | TransactionType::Transfer => match self.deposits.get(&transaction.client) {
+                Some(deposit) if now < deposit.saturating_add(self.window) => {
+                    Verdict::from_action(self.action, format!("withdrawal {} after deposit", now.saturating_sub(*deposit)))
+                },
+                _ => Verdict::Allow,
+            },
+            _ => Verdict::Allow,
+        }
+    }
+
+    fn record(&mut self, transaction: &Transaction) {
+        if transaction.trans_type == TransactionType::Deposit {
+            self.deposits.insert(transaction.client, transaction.time());
+        }
+    }
+}
+
+pub struct RepeatedChargebacks {
+    pub threshold: usize,
+    pub action: Action,
+    chargebacks: HashMap<ClientId, usize>,
+}
+
+impl RepeatedChargebacks {
+    pub fn new(threshold: usize, action: Action) -> RepeatedChargebacks {
+        RepeatedChargebacks { threshold, action, chargebacks: HashMap::new() }
+    }
+}
+
+impl Rule for RepeatedChargebacks {
+    fn name(&self) -> &'static str {
+        "repeated_chargebacks"
+    }
+
+    fn inspect(&self, transaction: &Transaction, account: &Account) -> Verdict {
+        if transaction.trans_type != TransactionType::ChargeBack {
+            return Verdict::Allow
+        }
+        let chargebacks = self.chargebacks.get(&transaction.client).copied().unwrap_or(0) + 1;
+
+        if chargebacks >= self.threshold {
+            Verdict::from_action(self.action, format!("chargeback number {} on {} account",
+                                                      chargebacks, if account.locked() { "locked" } else { "open" }))
+        } else {
+            Verdict::Allow
+        }
+    }
+
+    fn record(&mut self, transaction: &Transaction) {
+        if transaction.trans_type == TransactionType::ChargeBack {
+            *self.chargebacks.entry(transaction.client).or_insert(0) += 1;
+        }
+    }
+}
+
+// One row of the rules file; `window` is in the same time unit as the limits.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RuleConfig {
+    pub rule: String,
+    pub window: Option<u64>,
+    pub threshold: Option<usize>,
+    pub action: Action,
+}
+
+impl RuleConfig {
+    pub fn build(&self) -> Result<Box<dyn Rule>, String> {
+        let window = || self.window.ok_or(format!("Rule {} requires a window", self.rule));
+        let threshold = || self.threshold.ok_or(format!("Rule {} requires a threshold", self.rule));
+        match self.rule.as_str() {
+            "rapid_disputes" => Ok(Box::new(RapidDisputes::new(window()?, threshold()?, self.action))),
+            "deposit_withdrawal" => Ok(Box::new(DepositThenWithdrawal::new(window()?, self.action))),
+            "repeated_chargebacks" => Ok(Box::new(RepeatedChargebacks::new(threshold()?, self.action))),
+            _ => Err(format!("Unknown rule {}", self.rule)),
+        }
+    }
+}
+
+pub async fn load(reader: impl AsyncRead + Unpin + Send + Sync) -> Result<Vec<Box<dyn Rule>>, String> {
+    let configs = deserialize_records::<RuleConfig>(reader).await.map_err(|err| format!("Cannot parse rules {:?}", err))?;
+    configs.iter().map(RuleConfig::build).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::fs::File;
+    use crate::ids::{client_id, tx_id};
+    use crate::rules::*;
+
+    fn transaction(trans_type: TransactionType, client: u64, tx: u64, sequence: u64) -> Transaction {
+        Transaction {
+            client: client_id(client),
+            trans_type,
+            tx: tx_id(tx),
+            amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence,
+        }
+    }
+
+    // Inspects the transaction and records it as applied.
+    fn apply(rule: &mut dyn Rule, transaction: &Transaction, account: &Account) -> Verdict {
+        let verdict = rule.inspect(transaction, account);
+        rule.record(transaction);
+        verdict
+    }
+
+    #[test]
+    fn test_rapid_disputes() {
+        let account = Account::new(client_id(1));
+        let mut rule = RapidDisputes::new(10, 2, Action::Flag);
+
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::Dispute, 1, 1, 1), &account), Verdict::Allow);
+        assert!(matches!(apply(&mut rule, &transaction(TransactionType::Dispute, 1, 2, 5), &account), Verdict::Flag(_)));
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::Dispute, 1, 3, 20), &account), Verdict::Allow);
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::Deposit, 1, 4, 21), &account), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_deposit_then_withdrawal() {
+        let account = Account::new(client_id(1));
+        let mut rule = DepositThenWithdrawal::new(5, Action::Block);
+
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::Deposit, 1, 1, 1), &account), Verdict::Allow);
+        assert!(matches!(apply(&mut rule, &transaction(TransactionType::WithDrawal, 1, 2, 3), &account), Verdict::Block(_)));
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::WithDrawal, 2, 3, 3), &account), Verdict::Allow);
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::WithDrawal, 1, 4, 6), &account), Verdict::Allow);
+    }
+
+    #[test]
+    fn test_repeated_chargebacks() {
+        let account = Account::new(client_id(1));
+        let mut rule = RepeatedChargebacks::new(2, Action::Block);
+
+        assert_eq!(apply(&mut rule, &transaction(TransactionType::ChargeBack, 1, 1, 1), &account), Verdict::Allow);
+        assert!(matches!(apply(&mut rule, &transaction(TransactionType::ChargeBack, 1, 2, 2), &account), Verdict::Block(_)));
+    }
+
+    #[test]
+    fn test_inspect_does_not_record() {
+        let account = Account::new(client_id(1));
+        let mut rule = RepeatedChargebacks::new(2, Action::Block);
+
+        assert_eq!(rule.inspect(&transaction(TransactionType::ChargeBack, 1, 1, 1), &account), Verdict::Allow);
+        assert_eq!(rule.inspect(&transaction(TransactionType::ChargeBack, 1, 2, 2), &account), Verdict::Allow);
+        rule.record(&transaction(TransactionType::ChargeBack, 1, 2, 2));
+        assert!(matches!(rule.inspect(&transaction(TransactionType::ChargeBack, 1, 3, 3), &account), Verdict::Block(_)));
+
+        let rule = DepositThenWithdrawal::new(5, Action::Block);
+        assert_eq!(rule.inspect(&transaction(TransactionType::Deposit, 1, 1, 1), &account), Verdict::Allow);
+        assert_eq!(rule.inspect(&transaction(TransactionType::WithDrawal, 1, 2, 2), &account), Verdict::Allow);
+    }
+
+    #[tokio::test]
+    async fn test_rules_load() {
+        let file = File::open("test/rules.csv").await.unwrap();
+        let rules = load(file).await.unwrap();
+
+        let names: Vec<&str> = rules.iter().map(|rule| rule.name()).collect();
+        assert_eq!(names, vec!["rapid_disputes", "deposit_withdrawal", "repeated_chargebacks"]);
+    }
+
+    #[test]
+    fn test_rule_config_errors() {
+        let config = RuleConfig { rule: "rapid_disputes".to_owned(), window: None, threshold: Some(1), action: Action::Flag };
+        assert!(config.build().is_err());
+
+        let config = RuleConfig { rule: "unknown".to_owned(), window: Some(1), threshold: Some(1), action: Action::Flag };
+        assert!(config.build().is_err());
+    }
+}
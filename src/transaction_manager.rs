@@ -1,66 +1,731 @@
-use crate::csv_parser::{Transaction, TransactionType, TransactionError};
+use crate::csv_parser::{Transaction, TransactionType, TransactionError, TransactionStatus, MAX_PRECISION, format_timestamp};
+use crate::ids::{ClientId, TxId};
+use crate::directory::{AccountStatus, Directory, UnknownClientPolicy};
+use crate::fee::FeeSchedule;
+use crate::ledger::{Ledger, LedgerAccount};
+use crate::invariants::{self, Snapshot, Violation};
+use crate::limits::RiskControls;
+use crate::rules::{Alert, Action, Rule, Verdict};
+use crate::history::{BalancePoint, History};
+use crate::events::{AccountEvent, AccountState, DisputeState};
 use std::collections::HashMap;
+use tracing::{error, warn};
 
 use serde::Serialize;
-use std::fmt::{Debug};
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
 
 
 
+// Balances are a view over the ledger, refreshed after every posting touching the account.
 #[derive(Serialize, Debug)]
 pub struct Account {
     #[serde(rename = "client")]
-    id: u16,
+    id: ClientId,
     available: f64,
     held: f64,
     total: f64,
     locked: bool,
+    overdrawn: f64,
     #[serde(skip)]
-    transactions: HashMap< u32, (bool, Transaction)>,
+    last_activity: Option<u64>,
+    status: AccountStatus,
+    #[serde(skip)]
+    transactions: HashMap< TxId, TransactionRecord>,
+}
+
+// Account output row extended with the time of the latest applied transaction.
+#[derive(Serialize, Debug)]
+pub struct AccountActivity {
+    client: ClientId,
+    available: f64,
+    held: f64,
+    total: f64,
+    locked: bool,
+    overdrawn: f64,
+    status: AccountStatus,
+    last_activity: Option<String>,
+}
+
+#[derive(Debug)]
+struct TransactionRecord {
+    disputed: bool,
+    // The last dispute was resolved, until the transaction is disputed again.
+    resolved: bool,
+    reversed: bool,
+    // A charged back transaction is settled for good, it cannot be disputed or reversed again.
+    charged_back: bool,
+    // Amount moved to held by the open dispute.
+    held: f64,
+    transaction: Transaction,
+}
+
+impl TransactionRecord {
+    fn new(transaction: &Transaction) -> TransactionRecord {
+        TransactionRecord {
+            disputed: false,
+            resolved: false,
+            reversed: false,
+            charged_back: false,
+            held: 0.0,
+            transaction: transaction.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DisputePolicy {
+    AllowNegative,
+    Reject,
+    Cap,
+}
+
+impl FromStr for DisputePolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "allow" => Ok(DisputePolicy::AllowNegative),
+            "reject" => Ok(DisputePolicy::Reject),
+            "cap" => Ok(DisputePolicy::Cap),
+            _ => Err(format!("Unknown dispute policy {}, expected allow, reject or cap", policy)),
+        }
+    }
+}
+
+impl Display for DisputePolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisputePolicy::AllowNegative => {write!(f, "allow")}
+            DisputePolicy::Reject => {write!(f, "reject")}
+            DisputePolicy::Cap => {write!(f, "cap")}
+        }
+    }
+}
+
+// What a locked account refuses on top of transfers: nothing more, any outgoing funds, or every client transaction.
+// Operator reversals are never refused.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LockPolicy {
+    Transfers,
+    Outgoing,
+    Frozen,
+}
+
+impl LockPolicy {
+    fn refuses(&self, trans_type: TransactionType) -> bool {
+        match self {
+            LockPolicy::Transfers => false,
+            LockPolicy::Outgoing => matches!(trans_type, TransactionType::WithDrawal | TransactionType::Transfer),
+            LockPolicy::Frozen => trans_type != TransactionType::Reversal,
+        }
+    }
+
+    fn allows(&self, trans_type: TransactionType) -> bool {
+        trans_type != TransactionType::Transfer && !self.refuses(trans_type)
+    }
+}
+
+impl FromStr for LockPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "transfers" => Ok(LockPolicy::Transfers),
+            "outgoing" => Ok(LockPolicy::Outgoing),
+            "frozen" => Ok(LockPolicy::Frozen),
+            _ => Err(format!("Unknown lock policy {}, expected transfers, outgoing or frozen", policy)),
+        }
+    }
+}
+
+impl Display for LockPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockPolicy::Transfers => {write!(f, "transfers")}
+            LockPolicy::Outgoing => {write!(f, "outgoing")}
+            LockPolicy::Frozen => {write!(f, "frozen")}
+        }
+    }
 }
 
 impl Account {
-    fn new(id: u16) -> Account{
+    pub fn new(id: ClientId) -> Account{
         Account {
-                id: id,
+                id,
                 available: 0.0,
                 held: 0.0,
                 total: 0.0,
                 locked: false,
+                overdrawn: 0.0,
+                last_activity: None,
+                status: AccountStatus::Active,
                 transactions: HashMap::new(),
         }
     }
-}
 
-pub async fn process_transaction(accounts: &mut HashMap<u16, Account>, transaction: &Transaction) -> Result<(), TransactionError> {
-    if !valid_transaction_id(accounts, transaction).await {
-        return Err(TransactionError::ExistingTransactionId)
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn status(&self) -> AccountStatus {
+        self.status
+    }
+
+    pub fn activity(&self) -> AccountActivity {
+        AccountActivity {
+            client: self.id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            overdrawn: self.overdrawn,
+            status: self.status,
+            last_activity: self.last_activity.map(format_timestamp),
+        }
+    }
+
+    pub fn state(&self) -> AccountState {
+        AccountState {
+            client: self.id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            overdrawn: self.overdrawn,
+            status: self.status,
+        }
+    }
+
+    fn balance_point(&self, transaction: &Transaction) -> BalancePoint {
+        BalancePoint {
+            sequence: transaction.sequence,
+            tx: transaction.tx,
+            trans_type: transaction.trans_type,
+            timestamp: transaction.timestamp,
+            client: self.id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
+
+    fn event(&self, event: u64, transaction: &Transaction, fee: f64) -> AccountEvent {
+        let referenced = self.transactions.get(&transaction.tx);
+        let (amount, dispute) = match transaction.trans_type {
+            TransactionType::Dispute => (referenced.map(|record| record.held), DisputeState::Open),
+            TransactionType::Resolve => (referenced.map(|record| record.held), DisputeState::Resolved),
+            TransactionType::ChargeBack => (referenced.map(|record| record.held), DisputeState::ChargedBack),
+            TransactionType::Reversal => (referenced.and_then(|record| record.transaction.amount), DisputeState::None),
+            _ => (transaction.amount, DisputeState::None),
+        };
+        AccountEvent {
+            event,
+            client: self.id,
+            tx: Some(transaction.tx),
+            trans_type: transaction.trans_type,
+            timestamp: transaction.timestamp,
+            amount,
+            fee,
+            dispute,
+            reversed: referenced.is_some_and(|record| record.reversed),
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            overdrawn: self.overdrawn,
+            status: self.status,
+        }
+    }
+
+    // The `open` event of an account without an applied transaction: created by a rejected one or by the master file.
+    fn open_event(&self, event: u64, transaction: Option<&Transaction>) -> AccountEvent {
+        AccountEvent {
+            event,
+            client: self.id,
+            tx: transaction.map(|transaction| transaction.tx),
+            trans_type: TransactionType::Open,
+            timestamp: transaction.and_then(|transaction| transaction.timestamp),
+            amount: None,
+            fee: 0.0,
+            dispute: DisputeState::None,
+            reversed: false,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            overdrawn: self.overdrawn,
+            status: self.status,
+        }
+    }
+
+    fn touch(&mut self, timestamp: Option<u64>) {
+        if timestamp > self.last_activity {
+            self.last_activity = timestamp;
+        }
+    }
+
+    fn refresh(&mut self, ledger: &Ledger) {
+        self.available = ledger.balance(LedgerAccount::Available(self.id));
+        self.held = ledger.balance(LedgerAccount::Held(self.id));
+        self.total = self.available + self.held;
+        // What the client owes once a dispute or chargeback took more than was available.
+        self.overdrawn = if self.available < 0.0 { -self.available } else { 0.0 };
+    }
+
+    fn snapshot(&self, ledger: &Ledger) -> Snapshot {
+        Snapshot {
+            client: self.id,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+            ledger: ledger.balance(LedgerAccount::Available(self.id)) + ledger.balance(LedgerAccount::Held(self.id)),
+        }
     }
-    let account = accounts.entry(transaction.client).or_insert_with(||Account::new(transaction.client));
-    manage_transaction(account, transaction).await
 }
 
-async fn valid_transaction_id(accounts: &HashMap<u16, Account>, transaction: &Transaction) -> bool {
-    match transaction.trans_type {
-        TransactionType::Deposit | TransactionType::WithDrawal => {
-            for account in accounts.values() {
-                if account.transactions.contains_key(&transaction.tx) {
-                    return false
+pub struct TransactionManager {
+    accounts: HashMap<ClientId, Account>,
+    ledger: Ledger,
+    fees: FeeSchedule,
+    dispute_policy: DisputePolicy,
+    lock_policy: LockPolicy,
+    limits: RiskControls,
+    directory: Directory,
+    rules: Vec<Box<dyn Rule>>,
+    alerts: Vec<Alert>,
+    history: Option<History>,
+    events: Option<Vec<AccountEvent>>,
+    audit: bool,
+    violations: Vec<Violation>,
+    locked_accounts: usize,
+    // The client of every applied deposit, withdrawal and transfer, by tx id.
+    transaction_clients: HashMap<TxId, ClientId>,
+}
+
+impl TransactionManager {
+    pub fn new(fees: FeeSchedule) -> TransactionManager {
+        TransactionManager {
+            accounts: HashMap::new(),
+            ledger: Ledger::new(),
+            fees,
+            dispute_policy: DisputePolicy::AllowNegative,
+            lock_policy: LockPolicy::Transfers,
+            limits: RiskControls::default(),
+            directory: Directory::default(),
+            rules: Vec::new(),
+            alerts: Vec::new(),
+            history: None,
+            events: None,
+            audit: false,
+            violations: Vec::new(),
+            locked_accounts: 0,
+            transaction_clients: HashMap::new(),
+        }
+    }
+
+    pub fn set_dispute_policy(&mut self, policy: DisputePolicy) {
+        self.dispute_policy = policy;
+    }
+
+    pub fn set_lock_policy(&mut self, policy: LockPolicy) {
+        self.lock_policy = policy;
+    }
+
+    pub fn set_risk_controls(&mut self, limits: RiskControls) {
+        self.limits = limits;
+    }
+
+    // Opens every account of the master file and joins its tier and limit into the risk controls,
+    // so it comes after set_risk_controls.
+    pub fn set_directory(&mut self, directory: Directory) {
+        for record in directory.records() {
+            let account = self.accounts.entry(record.client).or_insert_with(||Account::new(record.client));
+            account.status = record.status();
+            self.limits.assign(record.client, record.tier.as_deref(), record.max_withdrawal);
+            self.open_event(record.client, None);
+        }
+        self.directory = directory;
+    }
+
+    pub fn directory(&self) -> &Directory {
+        &self.directory
+    }
+
+    pub fn add_rule(&mut self, rule: Box<dyn Rule>) {
+        self.rules.push(rule);
+    }
+
+    pub fn alerts(&self) -> &[Alert] {
+        &self.alerts
+    }
+
+    // Keeps the balances of every touched account after each applied transaction for point-in-time queries.
+    pub fn enable_history(&mut self) {
+        self.history = Some(History::new());
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // Keeps every applied transaction, per touched client, for the event stream export.
+    // Accounts that exist before the first transaction, as the ones of the master file, open the stream.
+    pub fn enable_events(&mut self) {
+        self.events = Some(Vec::new());
+        let mut clients: Vec<ClientId> = self.accounts.keys().copied().collect();
+        clients.sort();
+        for client in clients {
+            self.open_event(client, None);
+        }
+    }
+
+    pub fn events(&self) -> Option<&[AccountEvent]> {
+        self.events.as_deref()
+    }
+
+    // In audit mode every account touched by a transaction is checked against the invariants afterwards.
+    pub fn enable_audit(&mut self) {
+        self.audit = true;
+    }
+
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn accounts(&self) -> &HashMap<ClientId, Account> {
+        &self.accounts
+    }
+
+    // The accounts of the clients, without the house account collecting the fees.
+    pub fn client_accounts(&self) -> impl Iterator<Item = &Account> {
+        self.accounts.values().filter(move |account| !self.fees.is_house(account.id))
+    }
+
+    // How many accounts `client_accounts` yields, without walking them.
+    pub fn client_account_count(&self) -> usize {
+        let house = self.fees.house_account.is_some_and(|house| self.accounts.contains_key(&house));
+        self.accounts.len() - usize::from(house)
+    }
+
+    // Accounts are only ever locked by a chargeback and never unlocked, so a counter is enough.
+    pub fn locked_accounts(&self) -> usize {
+        self.locked_accounts
+    }
+
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub async fn process_transaction(&mut self, transaction: &Transaction) -> Result<TransactionStatus, TransactionError> {
+        let created = !self.accounts.contains_key(&transaction.client);
+        let result = if self.audit {
+            self.audit_transaction(transaction).await
+        } else {
+            self.apply_transaction(transaction).await
+        };
+        // A rejected transaction has no event, the account it created still opens its stream so that it is rebuilt.
+        if created && result.is_err() {
+            self.open_event(transaction.client, Some(transaction));
+        }
+        result
+    }
+
+    async fn audit_transaction(&mut self, transaction: &Transaction) -> Result<TransactionStatus, TransactionError> {
+        let mut clients = vec![transaction.client];
+        clients.extend(transaction.destination);
+        clients.extend(self.fees.house_account);
+        // The same account can be listed more than once, e.g. a row naming its own client as destination.
+        clients.sort();
+        clients.dedup();
+        let before: Vec<Option<Snapshot>> = clients.iter()
+            .map(|client| self.accounts.get(client).map(|account| account.snapshot(&self.ledger)))
+            .collect();
+
+        let result = self.apply_transaction(transaction).await;
+
+        let lock_allows = self.lock_policy.allows(transaction.trans_type);
+        for (client, before) in clients.iter().zip(before) {
+            if let Some(account) = self.accounts.get(client) {
+                let after = account.snapshot(&self.ledger);
+                for invariant in invariants::check(before.as_ref(), &after, lock_allows) {
+                    let violation = Violation {
+                        invariant,
+                        tx: transaction.tx,
+                        trans_type: transaction.trans_type,
+                        before: before.clone(),
+                        after: after.clone(),
+                    };
+                    error!(invariant = ?violation.invariant, "{}", violation);
+                    self.violations.push(violation);
+                }
+            }
+        }
+        result
+    }
+
+    async fn apply_transaction(&mut self, transaction: &Transaction) -> Result<TransactionStatus, TransactionError> {
+        // The house account only moves through fees, an input row cannot name it.
+        if self.fees.is_house(transaction.client) || transaction.destination.is_some_and(|destination| self.fees.is_house(destination)) {
+            return Err(TransactionError::HouseAccount)
+        }
+        if let TransactionStatus::Duplicate = check_transaction_id(&self.accounts, &self.transaction_clients, transaction).await? {
+            return Ok(TransactionStatus::Duplicate)
+        }
+        if transaction.trans_type.is_lifecycle() {
+            manage_lifecycle(&mut self.accounts, transaction)?;
+            self.record(transaction, vec![transaction.client], 0.0);
+            return Ok(TransactionStatus::Applied)
+        }
+        admit(&self.accounts, &self.directory, transaction.client, transaction.trans_type)?;
+        if let (TransactionType::Transfer, Some(destination)) = (transaction.trans_type, transaction.destination) {
+            admit(&self.accounts, &self.directory, destination, transaction.trans_type)?;
+        }
+        if self.accounts.get(&transaction.client).is_some_and(|account| account.locked && self.lock_policy.refuses(transaction.trans_type)) {
+            return Err(TransactionError::AccountLocked)
+        }
+        self.limits.check(transaction)?;
+        let account = self.accounts.entry(transaction.client).or_insert_with(||Account::new(transaction.client));
+        inspect(&self.rules, &mut self.alerts, transaction, account)?;
+        let fee = match fee_base(account, transaction) {
+            Some(amount) => self.fees.fee(transaction.trans_type, amount),
+            None => 0.0,
+        };
+
+        if let TransactionType::Transfer = transaction.trans_type {
+            manage_transfer(&mut self.accounts, &mut self.ledger, transaction, fee).await?;
+        } else {
+            let was_locked = account.locked;
+            manage_transaction(account, &mut self.ledger, transaction, fee, self.dispute_policy).await?;
+            if !was_locked && account.locked {
+                self.locked_accounts += 1;
+            }
+        }
+        if transaction.trans_type.is_new_transaction() {
+            self.transaction_clients.insert(transaction.tx, transaction.client);
+        }
+        let fee = match self.fees.house_account {
+            Some(house_account) if fee > 0.0 => charge_fee(&mut self.accounts, &mut self.ledger, house_account, transaction, fee),
+            _ => 0.0,
+        };
+        self.limits.record(transaction);
+        for rule in self.rules.iter_mut() {
+            rule.record(transaction);
+        }
+
+        let mut touched = vec![transaction.client];
+        touched.extend(transaction.destination);
+        if fee > 0.0 {
+            touched.extend(self.fees.house_account);
+        }
+        self.record(transaction, touched, fee);
+        Ok(TransactionStatus::Applied)
+    }
+
+    fn open_event(&mut self, client: ClientId, transaction: Option<&Transaction>) {
+        if self.fees.is_house(client) {
+            return
+        }
+        if let (Some(events), Some(account)) = (&mut self.events, self.accounts.get(&client)) {
+            let event = events.len() as u64 + 1;
+            events.push(account.open_event(event, transaction));
+        }
+    }
+
+    // The event stream only holds client accounts, as the report does, so that it rebuilds to the same accounts.
+    fn record(&mut self, transaction: &Transaction, touched: Vec<ClientId>, fee: f64) {
+        for client in touched {
+            let house = self.fees.is_house(client);
+            if let Some(account) = self.accounts.get_mut(&client) {
+                account.touch(transaction.timestamp);
+                if let Some(history) = &mut self.history {
+                    history.record(account.balance_point(transaction));
+                }
+                if let (Some(events), false) = (&mut self.events, house) {
+                    let event = events.len() as u64 + 1;
+                    events.push(account.event(event, transaction, fee));
                 }
             }
-            true
         }
-        _ => { true }
     }
 }
 
-async fn manage_transaction(account: &mut Account, transaction: &Transaction) -> Result<(), TransactionError> {
+// Clients missing from the master file are created on first use unless the directory rejects them.
+fn admit(accounts: &HashMap<ClientId, Account>, directory: &Directory, client: ClientId, trans_type: TransactionType) -> Result<(), TransactionError> {
+    match accounts.get(&client) {
+        Some(account) => account.status.check(trans_type),
+        None if directory.unknown_clients() == UnknownClientPolicy::Reject => Err(TransactionError::UnknownClient),
+        None => Ok(()),
+    }
+}
+
+// Every rule sees the transaction; flags only raise alerts, the first blocking rule rejects it.
+fn inspect(rules: &[Box<dyn Rule>], alerts: &mut Vec<Alert>, transaction: &Transaction, account: &Account) -> Result<(), TransactionError> {
+    let mut blocked_by = None;
+    for rule in rules.iter() {
+        let (action, reason) = match rule.inspect(transaction, account) {
+            Verdict::Allow => continue,
+            Verdict::Flag(reason) => (Action::Flag, reason),
+            Verdict::Block(reason) => (Action::Block, reason),
+        };
+        if action == Action::Block && blocked_by.is_none() {
+            blocked_by = Some(rule.name());
+        }
+        alerts.push(Alert {
+            rule: rule.name(),
+            action,
+            client: transaction.client,
+            tx: transaction.tx,
+            trans_type: transaction.trans_type,
+            reason,
+        });
+    }
+    match blocked_by {
+        Some(rule) => Err(TransactionError::BlockedByRule(rule)),
+        None => Ok(()),
+    }
+}
+
+fn fee_base(account: &Account, transaction: &Transaction) -> Option<f64> {
+    match transaction.trans_type {
+        TransactionType::Deposit | TransactionType::WithDrawal | TransactionType::Transfer => transaction.amount,
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::ChargeBack => {
+            account.transactions.get(&transaction.tx).and_then(|referenced| referenced.transaction.amount)
+        }
+        // Operator corrections and lifecycle operations are never charged.
+        TransactionType::Reversal | TransactionType::Open | TransactionType::Close | TransactionType::Suspend | TransactionType::Reactivate => None,
+    }
+}
+
+// The fee is booked as its own posting from the charged client to the house account. A fee the available funds left
+// by the transaction cannot cover is waived rather than overdrawing the client, the charged fee is returned.
+fn charge_fee(accounts: &mut HashMap<ClientId, Account>, ledger: &mut Ledger, house_account: ClientId, transaction: &Transaction, fee: f64) -> f64 {
+    if !accounts.get(&transaction.client).is_some_and(|account| has_sufficient_fund(account, fee)) {
+        warn!(fee, "Fee waived, insufficient funds");
+        return 0.0
+    }
+    ledger.post_fee(transaction.tx, transaction.trans_type, LedgerAccount::Available(transaction.client), LedgerAccount::Available(house_account), fee);
+
+    accounts.entry(transaction.client).or_insert_with(||Account::new(transaction.client)).refresh(ledger);
+    accounts.entry(house_account).or_insert_with(||Account::new(house_account)).refresh(ledger);
+    fee
+}
+
+// A reused tx id is only accepted for a retry of the same transaction; a retried dispute, resolve, chargeback or
+// reversal finds its tx already in the state it leaves it in.
+async fn check_transaction_id(accounts: &HashMap<ClientId, Account>, transaction_clients: &HashMap<TxId, ClientId>, transaction: &Transaction) -> Result<TransactionStatus, TransactionError> {
+    if transaction.trans_type.is_new_transaction() {
+        let record = transaction_clients.get(&transaction.tx)
+            .and_then(|client| accounts.get(client))
+            .and_then(|account| account.transactions.get(&transaction.tx));
+        return match record {
+            Some(record) if !is_resubmission(&record.transaction, transaction) => Err(TransactionError::ExistingTransactionId),
+            Some(_) => Ok(TransactionStatus::Duplicate),
+            None => Ok(TransactionStatus::Applied),
+        }
+    }
+    let record = accounts.get(&transaction.client).and_then(|account| account.transactions.get(&transaction.tx));
+    let repeated = record.is_some_and(|record| match transaction.trans_type {
+        TransactionType::Dispute => record.disputed,
+        TransactionType::Resolve => record.resolved,
+        TransactionType::ChargeBack => record.charged_back,
+        TransactionType::Reversal => record.reversed,
+        _ => false,
+    });
+    Ok(if repeated { TransactionStatus::Duplicate } else { TransactionStatus::Applied })
+}
+
+fn is_resubmission(applied: &Transaction, transaction: &Transaction) -> bool {
+    applied.trans_type == transaction.trans_type
+        && applied.client == transaction.client
+        && applied.amount == transaction.amount
+        && applied.destination == transaction.destination
+}
+
+fn has_sufficient_fund(account: &Account, amount: f64) -> bool {
+    (account.available - amount) >= 0.0
+}
+
+// Every check runs before either account is touched, so a rejected transfer leaves both sides unchanged.
+async fn manage_transfer(accounts: &mut HashMap<ClientId, Account>, ledger: &mut Ledger, transaction: &Transaction, fee: f64) -> Result<(), TransactionError> {
+    let amount = match transaction.amount {
+        Some(amount) if amount > 0.0 => amount,
+        Some(_) => return Err(TransactionError::InvalidTransferAmount),
+        None => return Err(TransactionError::NoAmountForTransaction)
+    };
+    let destination = match transaction.destination {
+        Some(destination) if destination != transaction.client => destination,
+        _ => return Err(TransactionError::InvalidTransferDestination)
+    };
+
+    let source = accounts.entry(transaction.client).or_insert_with(||Account::new(transaction.client));
+    if source.locked {
+        return Err(TransactionError::AccountLocked)
+    }
+    if !has_sufficient_fund(source, amount + fee) {
+        return Err(TransactionError::InsufficientFund)
+    }
+    if accounts.get(&destination).is_some_and(|account| account.locked) {
+        return Err(TransactionError::AccountLocked)
+    }
+
+    ledger.post(transaction.tx, transaction.trans_type, LedgerAccount::Available(transaction.client), LedgerAccount::Available(destination), amount);
+
+    let source = accounts.entry(transaction.client).or_insert_with(||Account::new(transaction.client));
+    source.transactions.insert(transaction.tx,TransactionRecord::new(transaction));
+    source.refresh(ledger);
+
+    let destination = accounts.entry(destination).or_insert_with(||Account::new(destination));
+    destination.transactions.insert(transaction.tx,TransactionRecord::new(transaction));
+    destination.refresh(ledger);
+    Ok(())
+}
+
+fn is_settled(amount: f64) -> bool {
+    (amount * 10f64.powi(MAX_PRECISION as i32)).round() == 0.0
+}
+
+// Open creates the account or reopens a closed one; the other operations need an existing account that is not closed.
+// They bypass limits, rules and fees, and a suspended account still takes them.
+fn manage_lifecycle(accounts: &mut HashMap<ClientId, Account>, transaction: &Transaction) -> Result<(), TransactionError> {
+    let account = match accounts.get_mut(&transaction.client) {
+        Some(account) => account,
+        None if transaction.trans_type == TransactionType::Open => {
+            accounts.insert(transaction.client, Account::new(transaction.client));
+            return Ok(())
+        },
+        None => return Err(TransactionError::AccountNotFound),
+    };
+
+    account.status = match (transaction.trans_type, account.status) {
+        (TransactionType::Open, AccountStatus::Closed) => AccountStatus::Active,
+        (TransactionType::Open, _) => return Err(TransactionError::AccountAlreadyOpen),
+        (_, AccountStatus::Closed) => return Err(TransactionError::AccountClosed),
+        (TransactionType::Close, _) if !is_settled(account.available) || !is_settled(account.held) => {
+            return Err(TransactionError::AccountBalanceNotZero)
+        },
+        (TransactionType::Close, _) => AccountStatus::Closed,
+        (TransactionType::Suspend, AccountStatus::Suspended) => return Err(TransactionError::AccountAlreadySuspended),
+        (TransactionType::Suspend, _) => AccountStatus::Suspended,
+        (TransactionType::Reactivate, AccountStatus::Suspended) => AccountStatus::Active,
+        (TransactionType::Reactivate, _) => return Err(TransactionError::AccountNotSuspended),
+        // Other types are never dispatched here.
+        (_, status) => status,
+    };
+    Ok(())
+}
+
+async fn manage_transaction(account: &mut Account, ledger: &mut Ledger, transaction: &Transaction, fee: f64, policy: DisputePolicy) -> Result<(), TransactionError> {
+    let available = LedgerAccount::Available(account.id);
+    let held = LedgerAccount::Held(account.id);
 
     match transaction.trans_type {
         TransactionType::Deposit => {
             if let Some(amount) = transaction.amount{
-                account.transactions.insert(transaction.tx,(false,transaction.to_owned()));
-                account.available += amount;
-                account.total += amount;
+                account.transactions.insert(transaction.tx,TransactionRecord::new(transaction));
+                ledger.post(transaction.tx, transaction.trans_type, LedgerAccount::Settlement, available, amount);
             }
             else {
                 return Err(TransactionError::NoAmountForTransaction)
@@ -68,57 +733,113 @@ async fn manage_transaction(account: &mut Account, transaction: &Transaction) ->
         },
         TransactionType::WithDrawal => {
             if let Some(amount) = transaction.amount{
-                if (account.available - amount) < 0.0  {
+                if !has_sufficient_fund(account, amount + fee) {
                     return Err(TransactionError::InsufficientFund)
                 }
 
-                account.transactions.insert(transaction.tx,(false,transaction.to_owned()));
-                account.available -= amount;
-                account.total -= amount;
+                account.transactions.insert(transaction.tx,TransactionRecord::new(transaction));
+                ledger.post(transaction.tx, transaction.trans_type, available, LedgerAccount::Settlement, amount);
             }
             else {
                 return Err(TransactionError::NoAmountForTransaction)
             }
         },
         TransactionType::Dispute => {
-            if !account.transactions.contains_key(&transaction.tx) {
-                return Err(TransactionError::InvalidReferencedTransaction)
+            let referenced_trans_with_dispute = match account.transactions.get_mut(&transaction.tx) {
+                Some(record) => record,
+                None => return Err(TransactionError::InvalidReferencedTransaction)
+            };
+            let referenced_transaction = &referenced_trans_with_dispute.transaction;
+            // Only the receiving side of a transfer can dispute it; the held amount is the credited one, as for a deposit.
+            if let TransactionType::Transfer = referenced_transaction.trans_type {
+                if referenced_transaction.client == account.id {
+                    return Err(TransactionError::TransactionNotDisputable)
+                }
+            }
+            if referenced_trans_with_dispute.reversed || referenced_trans_with_dispute.charged_back {
+                return Err(TransactionError::TransactionNotDisputable)
             }
-            let referenced_trans_with_dispute = account.transactions.get_mut(&transaction.tx).unwrap();
-            let referenced_transaction = &referenced_trans_with_dispute.1;
 
-            referenced_trans_with_dispute.0 = true;
-            account.available -= referenced_transaction.amount.unwrap();
-            account.held += referenced_transaction.amount.unwrap();
+            let amount = match referenced_transaction.amount {
+                Some(amount) => amount,
+                None => return Err(TransactionError::NoAmountForTransaction)
+            };
+            let covered = account.available.max(0.0);
+            let hold = match policy {
+                DisputePolicy::AllowNegative => amount,
+                DisputePolicy::Reject if amount > covered => return Err(TransactionError::InsufficientFundForDispute),
+                DisputePolicy::Reject => amount,
+                DisputePolicy::Cap => amount.min(covered),
+            };
+
+            referenced_trans_with_dispute.disputed = true;
+            referenced_trans_with_dispute.resolved = false;
+            referenced_trans_with_dispute.held = hold;
+            if hold < amount {
+                warn!(client = %account.id, tx = %transaction.tx, shortfall = amount - hold, "Dispute hold capped at the available funds");
+            }
+            ledger.post(transaction.tx, transaction.trans_type, available, held, hold);
         },
         TransactionType::Resolve => {
-            if !account.transactions.contains_key(&transaction.tx) {
-                return Err(TransactionError::InvalidReferencedTransaction)
-            }
-            let referenced_trans_with_dispute = account.transactions.get_mut(&transaction.tx).unwrap();
-            let referenced_transaction = &referenced_trans_with_dispute.1;
-            if !referenced_trans_with_dispute.0 {
+            let referenced_trans_with_dispute = match account.transactions.get_mut(&transaction.tx) {
+                Some(record) => record,
+                None => return Err(TransactionError::InvalidReferencedTransaction)
+            };
+            if !referenced_trans_with_dispute.disputed {
                 return Err(TransactionError::ReferencedTransactionIsNotDisputed)
             }
-            referenced_trans_with_dispute.0 = false;
-            account.available += referenced_transaction.amount.unwrap();
-            account.held -= referenced_transaction.amount.unwrap();
+            referenced_trans_with_dispute.disputed = false;
+            referenced_trans_with_dispute.resolved = true;
+            ledger.post(transaction.tx, transaction.trans_type, held, available, referenced_trans_with_dispute.held);
         },
         TransactionType::ChargeBack => {
-            if !account.transactions.contains_key(&transaction.tx) {
-                return Err(TransactionError::InvalidReferencedTransaction)
-            }
-            let referenced_trans_with_dispute = account.transactions.get_mut(&transaction.tx).unwrap();
-            let referenced_transaction = &referenced_trans_with_dispute.1;
-            if !referenced_trans_with_dispute.0 {
+            let referenced_trans_with_dispute = match account.transactions.get_mut(&transaction.tx) {
+                Some(record) => record,
+                None => return Err(TransactionError::InvalidReferencedTransaction)
+            };
+            if !referenced_trans_with_dispute.disputed {
                 return Err(TransactionError::ReferencedTransactionIsNotDisputed)
             }
-            referenced_trans_with_dispute.0 = false;
-            account.total-= referenced_transaction.amount.unwrap();
-            account.held -= referenced_transaction.amount.unwrap();
+            referenced_trans_with_dispute.disputed = false;
+            referenced_trans_with_dispute.charged_back = true;
+            ledger.post(transaction.tx, transaction.trans_type, held, LedgerAccount::ChargebackLoss, referenced_trans_with_dispute.held);
             account.locked = true;
         },
+        // Voids a deposit or withdrawal with the opposite posting; the original stays recorded and marked reversed.
+        TransactionType::Reversal => {
+            let referenced = match account.transactions.get_mut(&transaction.tx) {
+                Some(record) => record,
+                None => return Err(TransactionError::InvalidReferencedTransaction)
+            };
+            if referenced.disputed || referenced.charged_back {
+                return Err(TransactionError::TransactionNotReversible)
+            }
+            let amount = match referenced.transaction.amount {
+                Some(amount) => amount,
+                None => return Err(TransactionError::NoAmountForTransaction)
+            };
+            match referenced.transaction.trans_type {
+                TransactionType::Deposit => {
+                    if account.available < amount {
+                        return Err(TransactionError::InsufficientFund)
+                    }
+                    ledger.post(transaction.tx, transaction.trans_type, available, LedgerAccount::Settlement, amount);
+                },
+                TransactionType::WithDrawal => {
+                    ledger.post(transaction.tx, transaction.trans_type, LedgerAccount::Settlement, available, amount);
+                },
+                _ => return Err(TransactionError::TransactionNotReversible),
+            }
+            referenced.reversed = true;
+        },
+        TransactionType::Transfer => {
+            // Transfers touch two accounts and are handled by manage_transfer.
+            return Err(TransactionError::InvalidTransferDestination)
+        },
+        // Lifecycle operations move no funds and are handled by manage_lifecycle.
+        TransactionType::Open | TransactionType::Close | TransactionType::Suspend | TransactionType::Reactivate => {},
     }
+    account.refresh(ledger);
     Ok(())
 }
 
@@ -126,37 +847,57 @@ async fn manage_transaction(account: &mut Account, transaction: &Transaction) ->
 mod tests {
     use matches::assert_matches;
     use crate::transaction_manager::*;
-    use std::collections::HashMap;
+    use crate::directory::ClientRecord;
+    use crate::ids::{client_id, tx_id};
+    use crate::fee::{DEFAULT_HOUSE_ACCOUNT, FeeKind, FeeRule};
+    use crate::invariants::Invariant;
+    use crate::limits::{TierLimits, DEFAULT_TIER};
+    use crate::rules::{DepositThenWithdrawal, RepeatedChargebacks};
+    use crate::history::AsOf;
+    use crate::events::{self, AccountState};
+    use proptest::prelude::*;
+
+    fn fund(ledger: &mut Ledger, account: &mut Account, amount: f64) {
+        ledger.post(tx_id(0), TransactionType::Deposit, LedgerAccount::Settlement, LedgerAccount::Available(account.id), amount);
+        account.refresh(ledger);
+    }
 
     #[tokio::test]
     async fn test_account_create(){
-        let mut accounts = HashMap::new();
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(process_transaction(&mut accounts, &transaction).await, Ok(_));
+        assert_matches!(manager.process_transaction(&transaction).await, Ok(_));
 
-        assert!(!accounts.is_empty());
-        assert_eq!(accounts.get(&transaction.client).unwrap().id, 1);
+        assert!(!manager.accounts.is_empty());
+        assert_eq!(manager.accounts.get(&transaction.client).unwrap().id, client_id(1));
     }
 
     #[tokio::test]
     async fn test_transaction_deposit(){
-        let mut account = Account::new(1);
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Ok(_));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Ok(_));
 
         assert_eq!(account.available, 1.0);
         assert_eq!(account.total, 1.0);
@@ -166,31 +907,38 @@ mod tests {
 
     #[tokio::test]
     async fn test_transaction_deposit_error_no_amount(){
-        let mut account = Account::new(1);
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::NoAmountForTransaction));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::NoAmountForTransaction));
     }
 
     #[tokio::test]
     async fn test_transaction_withdrawal(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::WithDrawal,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Ok(_));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Ok(_));
 
         assert_eq!(account.available, 0.0);
         assert_eq!(account.total, 0.0);
@@ -198,187 +946,226 @@ mod tests {
 
     #[tokio::test]
     async fn test_transaction_withdrawal_error_insufficient_fund(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::WithDrawal,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(2.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
 
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::InsufficientFund));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::InsufficientFund));
     }
 
     #[tokio::test]
     async fn test_transaction_withdrawal_error_no_amount(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::WithDrawal,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
 
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::NoAmountForTransaction));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::NoAmountForTransaction));
     }
 
 
 
     #[tokio::test]
     async fn test_transaction_dispute(){
-        let mut account = Account::new(1);
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
 
         let deposit = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        manage_transaction(&mut account, &deposit).await.unwrap();
+        manage_transaction(&mut account, &mut ledger, &deposit, 0.0, DisputePolicy::AllowNegative).await.unwrap();
 
         assert_eq!(account.available, 1.0);
         assert_eq!(account.total, 1.0);
         assert_eq!(account.held, 0.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Dispute,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await,Ok(()));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await,Ok(()));
         assert_eq!(account.available, 0.0);
         assert_eq!(account.total, 1.0);
         assert_eq!(account.held, 1.0);
 
-        assert_eq!(account.transactions.get(&1).unwrap().0, true);
+        assert!(account.transactions.get(&tx_id(1)).unwrap().disputed);
     }
 
     #[tokio::test]
     async fn test_transaction_dispute_error_invalid_referenced_trans(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Dispute,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::InvalidReferencedTransaction));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::InvalidReferencedTransaction));
     }
 
 
 
     #[tokio::test]
     async fn test_transaction_resolve(){
-        let mut account = Account::new(1);
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
 
         let deposit = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        manage_transaction(&mut account, &deposit).await.unwrap();
+        manage_transaction(&mut account, &mut ledger, &deposit, 0.0, DisputePolicy::AllowNegative).await.unwrap();
 
         assert_eq!(account.available, 1.0);
         assert_eq!(account.total, 1.0);
         assert_eq!(account.held, 0.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Dispute,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await,Ok(()));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await,Ok(()));
         assert_eq!(account.available, 0.0);
         assert_eq!(account.total, 1.0);
         assert_eq!(account.held, 1.0);
 
-        assert_eq!(account.transactions.get(&1).unwrap().0, true);
+        assert!(account.transactions.get(&tx_id(1)).unwrap().disputed);
 
         let resolve = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Resolve,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &resolve).await,Ok(()));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &resolve, 0.0, DisputePolicy::AllowNegative).await,Ok(()));
         assert_eq!(account.available, 1.0);
         assert_eq!(account.total, 1.0);
         assert_eq!(account.held, 0.0);
 
-        assert_eq!(account.transactions.get(&1).unwrap().0, false);
+        assert!(!account.transactions.get(&tx_id(1)).unwrap().disputed);
     }
 
     #[tokio::test]
     async fn test_transaction_resolve_error_invalid_referenced_trans(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Resolve,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::InvalidReferencedTransaction));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::InvalidReferencedTransaction));
     }
 
     #[tokio::test]
     async fn test_transaction_resolve_error_referenced_trans_not_dispute(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        account.transactions.insert(transaction.tx,(false,transaction));
+        account.transactions.insert(transaction.tx,TransactionRecord::new(&transaction));
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Resolve,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::ReferencedTransactionIsNotDisputed));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::ReferencedTransactionIsNotDisputed));
     }
 
     #[tokio::test]
     async fn test_transaction_chargeback(){
-        let mut account = Account::new(1);
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
 
         let deposit = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        manage_transaction(&mut account, &deposit).await.unwrap();
+        manage_transaction(&mut account, &mut ledger, &deposit, 0.0, DisputePolicy::AllowNegative).await.unwrap();
 
         println!("{:?}",account);
 
@@ -387,108 +1174,1060 @@ mod tests {
         assert_eq!(account.held, 0.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Dispute,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await,Ok(()));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await,Ok(()));
         assert_eq!(account.available, 0.0);
         assert_eq!(account.total, 1.0);
         assert_eq!(account.held, 1.0);
 
-        assert_eq!(account.transactions.get(&1).unwrap().0, true);
+        assert!(account.transactions.get(&tx_id(1)).unwrap().disputed);
 
         let chargeback = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::ChargeBack,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &chargeback).await,Ok(()));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &chargeback, 0.0, DisputePolicy::AllowNegative).await,Ok(()));
         assert_eq!(account.available, 0.0);
         assert_eq!(account.total, 0.0);
         assert_eq!(account.held, 0.0);
 
-        assert_eq!(account.locked, true);
+        assert!(account.locked);
     }
 
     #[tokio::test]
     async fn test_transaction_chargeback_error_invalid_referenced_trans(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::ChargeBack,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::InvalidReferencedTransaction));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::InvalidReferencedTransaction));
     }
 
     #[tokio::test]
     async fn test_transaction_chargeback_error_referenced_trans_not_dispute(){
-        let mut account = Account::new(1);
-        account.available = 1.0;
-        account.total = 1.0;
+        let mut ledger = Ledger::new();
+        let mut account = Account::new(client_id(1));
+        fund(&mut ledger, &mut account, 1.0);
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        account.transactions.insert(transaction.tx,(false,transaction));
+        account.transactions.insert(transaction.tx,TransactionRecord::new(&transaction));
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::ChargeBack,
-            tx: 1,
+            tx: tx_id(1),
             amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(manage_transaction(&mut account, &transaction).await, Err(TransactionError::ReferencedTransactionIsNotDisputed));
+        assert_matches!(manage_transaction(&mut account, &mut ledger, &transaction, 0.0, DisputePolicy::AllowNegative).await, Err(TransactionError::ReferencedTransactionIsNotDisputed));
     }
 
     #[tokio::test]
     async fn test_process_transaction_error_transaction_id_not_unique(){
 
-        let mut accounts = HashMap::new();
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
 
 
         let transaction = Transaction {
-            client: 1,
+            client: client_id(1),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(process_transaction(&mut accounts, &transaction).await, Ok(_));
+        assert_matches!(manager.process_transaction(&transaction).await, Ok(_));
 
         let transaction_overlap_deposit = Transaction {
-            client: 2,
+            client: client_id(2),
             trans_type : TransactionType::Deposit,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(process_transaction(&mut accounts, &transaction_overlap_deposit).await, Err(TransactionError::ExistingTransactionId));
+        assert_matches!(manager.process_transaction(&transaction_overlap_deposit).await, Err(TransactionError::ExistingTransactionId));
 
         let transaction_overlap_withdrawal = Transaction {
-            client: 3,
+            client: client_id(3),
             trans_type : TransactionType::WithDrawal,
-            tx: 1,
+            tx: tx_id(1),
             amount: Some(1.0),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
         };
 
-        assert_matches!(process_transaction(&mut accounts, &transaction_overlap_withdrawal).await, Err(TransactionError::ExistingTransactionId));
+        assert_matches!(manager.process_transaction(&transaction_overlap_withdrawal).await, Err(TransactionError::ExistingTransactionId));
+    }
+
+    fn transfer(client: u64, destination: Option<u64>, tx: u64, amount: Option<f64>) -> Transaction {
+        Transaction {
+            client: client_id(client),
+            trans_type : TransactionType::Transfer,
+            tx: tx_id(tx),
+            amount,
+            destination: destination.map(client_id),
+            timestamp: None,
+            sequence: 0,
+        }
     }
-}
 
+    async fn deposit(manager: &mut TransactionManager, client: u64, tx: u64, amount: f64) {
+        let deposit = Transaction {
+            client: client_id(client),
+            trans_type : TransactionType::Deposit,
+            tx: tx_id(tx),
+            amount: Some(amount),
+            destination: None,
+            timestamp: None,
+            sequence: 0,
+        };
+        manager.process_transaction(&deposit).await.unwrap();
+    }
 
+    #[tokio::test]
+    async fn test_transfer(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 5.0).await;
+
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 2, Some(3.0))).await, Ok(TransactionStatus::Applied));
+
+        let source = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(source.available, 2.0);
+        assert_eq!(source.total, 2.0);
+        let destination = manager.accounts.get(&client_id(2)).unwrap();
+        assert_eq!(destination.available, 3.0);
+        assert_eq!(destination.total, 3.0);
+        assert!(destination.transactions.contains_key(&tx_id(2)));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_error_insufficient_fund_leaves_both_accounts_unchanged(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 1.0).await;
+        deposit(&mut manager, 2, 2, 1.0).await;
+
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 3, Some(2.0))).await, Err(TransactionError::InsufficientFund));
+
+        let source = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(source.available, 1.0);
+        assert_eq!(source.total, 1.0);
+        assert!(!source.transactions.contains_key(&tx_id(3)));
+        let destination = manager.accounts.get(&client_id(2)).unwrap();
+        assert_eq!(destination.available, 1.0);
+        assert_eq!(destination.total, 1.0);
+        assert!(!destination.transactions.contains_key(&tx_id(3)));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_error_locked_destination_leaves_source_unchanged(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 5.0).await;
+        deposit(&mut manager, 2, 2, 1.0).await;
+        manager.accounts.get_mut(&client_id(2)).unwrap().locked = true;
+
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 3, Some(2.0))).await, Err(TransactionError::AccountLocked));
+
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 5.0);
+        assert!(!manager.accounts.get(&client_id(1)).unwrap().transactions.contains_key(&tx_id(3)));
+        assert_eq!(manager.accounts.get(&client_id(2)).unwrap().available, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_error_locked_source(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 5.0).await;
+        manager.accounts.get_mut(&client_id(1)).unwrap().locked = true;
+
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 2, Some(2.0))).await, Err(TransactionError::AccountLocked));
+
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 5.0);
+        assert!(!manager.accounts.contains_key(&client_id(2)));
+    }
+
+    #[tokio::test]
+    async fn test_lock_policies(){
+        let locked = |policy: LockPolicy| async move {
+            let mut manager = TransactionManager::new(FeeSchedule::empty());
+            manager.set_lock_policy(policy);
+            deposit(&mut manager, 1, 1, 5.0).await;
+            deposit(&mut manager, 1, 2, 5.0).await;
+            manager.accounts.get_mut(&client_id(1)).unwrap().locked = true;
+            manager
+        };
+
+        let mut manager = locked(LockPolicy::Transfers).await;
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 3, Some(1.0))).await, Ok(TransactionStatus::Applied));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 4, Some(1.0))).await, Err(TransactionError::AccountLocked));
+
+        let mut manager = locked(LockPolicy::Outgoing).await;
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 3, Some(1.0))).await, Err(TransactionError::AccountLocked));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 1, 4, Some(1.0))).await, Ok(TransactionStatus::Applied));
+
+        let mut manager = locked(LockPolicy::Frozen).await;
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 1, 3, Some(1.0))).await, Err(TransactionError::AccountLocked));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await, Err(TransactionError::AccountLocked));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 2, None)).await, Ok(TransactionStatus::Applied));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 5.0);
+
+        assert_eq!("frozen".parse::<LockPolicy>(), Ok(LockPolicy::Frozen));
+        assert!("closed".parse::<LockPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_directory(){
+        let record = |client: u64, status: Option<AccountStatus>, max_withdrawal: Option<f64>| ClientRecord {
+            client: client_id(client), name: None, tier: None, status, currency: None, max_withdrawal,
+        };
+        let directory = |unknown_clients: UnknownClientPolicy| Directory::new(vec![
+            record(1, None, Some(3.0)),
+            record(2, Some(AccountStatus::Suspended), None),
+            record(3, Some(AccountStatus::Closed), None),
+        ], unknown_clients);
+
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_directory(directory(UnknownClientPolicy::Reject));
+        assert_eq!(manager.accounts.len(), 3);
+        assert_eq!(manager.accounts.get(&client_id(2)).unwrap().status(), AccountStatus::Suspended);
+
+        deposit(&mut manager, 1, 1, 5.0).await;
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(4.0))).await, Err(TransactionError::WithdrawalLimitExceeded));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 2, 3, Some(1.0))).await, Err(TransactionError::AccountSuspended));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 3, 4, Some(1.0))).await, Err(TransactionError::AccountClosed));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 4, 5, Some(1.0))).await, Err(TransactionError::UnknownClient));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 6, Some(1.0))).await, Err(TransactionError::AccountSuspended));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(4), 7, Some(1.0))).await, Err(TransactionError::UnknownClient));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 5.0);
+        assert!(!manager.accounts.contains_key(&client_id(4)));
+
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_directory(directory(UnknownClientPolicy::Create));
+        deposit(&mut manager, 4, 1, 1.0).await;
+        assert_eq!(manager.accounts.get(&client_id(4)).unwrap().status(), AccountStatus::Active);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_directory(Directory::new(Vec::new(), UnknownClientPolicy::Reject));
+        let lifecycle = |trans_type: TransactionType, client: u64| transaction(trans_type, client, 0, None);
+
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Suspend, 1)).await, Err(TransactionError::AccountNotFound));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Open, 1)).await, Ok(TransactionStatus::Applied));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Open, 1)).await, Err(TransactionError::AccountAlreadyOpen));
+        deposit(&mut manager, 1, 1, 5.0).await;
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Reactivate, 1)).await, Err(TransactionError::AccountNotSuspended));
+
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Suspend, 1)).await, Ok(TransactionStatus::Applied));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Suspend, 1)).await, Err(TransactionError::AccountAlreadySuspended));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(5.0))).await, Err(TransactionError::AccountSuspended));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Close, 1)).await, Err(TransactionError::AccountBalanceNotZero));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Reactivate, 1)).await, Ok(TransactionStatus::Applied));
+
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(5.0))).await, Ok(TransactionStatus::Applied));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Close, 1)).await, Ok(TransactionStatus::Applied));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().status(), AccountStatus::Closed);
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Suspend, 1)).await, Err(TransactionError::AccountClosed));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 1, 3, Some(1.0))).await, Err(TransactionError::AccountClosed));
+        assert_matches!(manager.process_transaction(&lifecycle(TransactionType::Open, 1)).await, Ok(TransactionStatus::Applied));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().status(), AccountStatus::Active);
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().transactions.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_ignores_lock_and_limits(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_lock_policy(LockPolicy::Frozen);
+        manager.set_risk_controls(RiskControls::new(vec![TierLimits {
+            tier: DEFAULT_TIER.to_owned(), max_withdrawal: None, window: Some(100), max_window_withdrawal: None, max_window_transactions: Some(1),
+        }], Vec::new()));
+        deposit(&mut manager, 1, 1, 5.0).await;
+        manager.accounts.get_mut(&client_id(1)).unwrap().locked = true;
+
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Suspend, 1, 0, None)).await, Ok(TransactionStatus::Applied));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reactivate, 1, 0, None)).await, Ok(TransactionStatus::Applied));
+        assert!(manager.accounts.get(&client_id(1)).unwrap().locked());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_error_invalid_destination(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 5.0).await;
+
+        assert_matches!(manager.process_transaction(&transfer(1, None, 2, Some(2.0))).await, Err(TransactionError::InvalidTransferDestination));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(1), 3, Some(2.0))).await, Err(TransactionError::InvalidTransferDestination));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 4, None)).await, Err(TransactionError::NoAmountForTransaction));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_error_amount_not_positive(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 2, 1, 10.0).await;
+
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 2, Some(-10.0))).await, Err(TransactionError::InvalidTransferAmount));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 3, Some(0.0))).await, Err(TransactionError::InvalidTransferAmount));
+
+        assert!(manager.accounts.get(&client_id(1)).is_none_or(|account| account.total == 0.0));
+        assert_eq!(manager.accounts.get(&client_id(2)).unwrap().available, 10.0);
+        assert_eq!(manager.accounts.get(&client_id(2)).unwrap().total, 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_dispute(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 5.0).await;
+        manager.process_transaction(&transfer(1, Some(2), 2, Some(3.0))).await.unwrap();
+
+        let sender_dispute = Transaction {
+            client: client_id(1),
+            trans_type : TransactionType::Dispute,
+            tx: tx_id(2),
+            amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
+        };
+        assert_matches!(manager.process_transaction(&sender_dispute).await, Err(TransactionError::TransactionNotDisputable));
+
+        let receiver_dispute = Transaction {
+            client: client_id(2),
+            trans_type : TransactionType::Dispute,
+            tx: tx_id(2),
+            amount: None,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
+        };
+        assert_matches!(manager.process_transaction(&receiver_dispute).await, Ok(TransactionStatus::Applied));
+
+        let destination = manager.accounts.get(&client_id(2)).unwrap();
+        assert_eq!(destination.available, 0.0);
+        assert_eq!(destination.held, 3.0);
+        assert_eq!(destination.total, 3.0);
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 2.0);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_error_transaction_id_not_unique(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        deposit(&mut manager, 1, 1, 5.0).await;
+
+        assert_matches!(manager.process_transaction(&transfer(1, Some(2), 1, Some(1.0))).await, Err(TransactionError::ExistingTransactionId));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 5.0);
+    }
+
+    fn fee_schedule() -> FeeSchedule {
+        FeeSchedule::new(DEFAULT_HOUSE_ACCOUNT, vec![
+            FeeRule { trans_type: TransactionType::WithDrawal, kind: FeeKind::Flat, value: 0.5, from: None, cap: None },
+            FeeRule { trans_type: TransactionType::ChargeBack, kind: FeeKind::Percentage, value: 10.0, from: None, cap: None },
+        ])
+    }
+
+    fn transaction(trans_type: TransactionType, client: u64, tx: u64, amount: Option<f64>) -> Transaction {
+        Transaction {
+            client: client_id(client),
+            trans_type,
+            tx: tx_id(tx),
+            amount,
+            destination: None,
+            timestamp: None,
+            sequence: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_house_account_only_with_fees(){
+        let house_deposit = Transaction { client: DEFAULT_HOUSE_ACCOUNT, ..transaction(TransactionType::Deposit, 1, 1, Some(5.0)) };
+
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.process_transaction(&house_deposit).await.unwrap();
+        assert_eq!(manager.client_accounts().map(|account| account.id).collect::<Vec<ClientId>>(), vec![DEFAULT_HOUSE_ACCOUNT]);
+        assert_eq!(manager.client_account_count(), 1);
+
+        let mut manager = TransactionManager::new(fee_schedule());
+        assert_matches!(manager.process_transaction(&house_deposit).await, Err(TransactionError::HouseAccount));
+        deposit(&mut manager, 1, 2, 5.0).await;
+        let to_house = Transaction { destination: Some(DEFAULT_HOUSE_ACCOUNT), ..transfer(1, None, 3, Some(1.0)) };
+        assert_matches!(manager.process_transaction(&to_house).await, Err(TransactionError::HouseAccount));
+        assert_eq!(manager.accounts()[&client_id(1)].available, 5.0);
+        assert!(!manager.accounts().contains_key(&DEFAULT_HOUSE_ACCOUNT));
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_fee_credited_to_house_account(){
+        let mut manager = TransactionManager::new(fee_schedule());
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(5.0))).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(2.0))).await, Ok(TransactionStatus::Applied));
+
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 2.5);
+        assert_eq!(account.total, 2.5);
+
+        let house = manager.accounts.get(&DEFAULT_HOUSE_ACCOUNT).unwrap();
+        assert_eq!(house.available, 0.5);
+        assert_eq!(house.total, 0.5);
+        assert_eq!(manager.client_account_count(), 1);
+        assert_eq!(manager.client_accounts().count(), 1);
+
+        let fee = manager.ledger.postings().iter().find(|posting| posting.fee).unwrap();
+        assert_eq!(fee.tx, tx_id(2));
+        assert_eq!(fee.debit, LedgerAccount::Available(client_id(1)));
+        assert_eq!(fee.credit, LedgerAccount::Available(DEFAULT_HOUSE_ACCOUNT));
+        assert_eq!(fee.amount, 0.5);
+        assert_eq!(manager.ledger.trial_balance(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_withdrawal_fee_counted_in_insufficient_fund_check(){
+        let mut manager = TransactionManager::new(fee_schedule());
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(2.0))).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(2.0))).await, Err(TransactionError::InsufficientFund));
+
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 2.0);
+        assert!(!manager.accounts.contains_key(&DEFAULT_HOUSE_ACCOUNT));
+    }
+
+    #[tokio::test]
+    async fn test_chargeback_fee_on_referenced_amount(){
+        let mut manager = TransactionManager::new(fee_schedule());
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(5.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 2, Some(10.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 2, None)).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 2, None)).await, Ok(TransactionStatus::Applied));
+
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 4.0);
+        assert_eq!(account.total, 4.0);
+        assert!(account.locked);
+        assert_eq!(manager.accounts.get(&DEFAULT_HOUSE_ACCOUNT).unwrap().total, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_fee_waived_when_funds_are_insufficient(){
+        let mut manager = TransactionManager::new(fee_schedule());
+        manager.enable_events();
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(5.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await, Ok(TransactionStatus::Applied));
+
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert!(account.locked);
+        assert!(!manager.accounts.contains_key(&DEFAULT_HOUSE_ACCOUNT));
+        assert!(manager.ledger.postings().iter().all(|posting| !posting.fee));
+        assert_eq!(manager.events().unwrap().last().unwrap().fee, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_trial_balance_after_mixed_run(){
+        let mut manager = TransactionManager::new(fee_schedule());
+
+        let transactions = vec![
+            transaction(TransactionType::Deposit, 1, 1, Some(10.0)),
+            transaction(TransactionType::Deposit, 2, 2, Some(3.0)),
+            transaction(TransactionType::WithDrawal, 1, 3, Some(2.0)),
+            transfer(1, Some(2), 4, Some(1.5)),
+            transaction(TransactionType::Dispute, 2, 2, None),
+            transaction(TransactionType::ChargeBack, 2, 2, None),
+            transaction(TransactionType::Dispute, 1, 1, None),
+            transaction(TransactionType::Resolve, 1, 1, None),
+            transaction(TransactionType::WithDrawal, 1, 5, Some(100.0)),
+        ];
+        for transaction in &transactions {
+            let _ = manager.process_transaction(transaction).await;
+        }
+
+        assert_eq!(manager.ledger().trial_balance(), 0.0);
+        assert_eq!(manager.ledger().balance(LedgerAccount::Settlement), -11.0);
+        assert_eq!(manager.ledger().balance(LedgerAccount::ChargebackLoss), 3.0);
+        for account in manager.accounts().values() {
+            assert_eq!(account.available, manager.ledger().balance(LedgerAccount::Available(account.id)));
+            assert_eq!(account.held, manager.ledger().balance(LedgerAccount::Held(account.id)));
+            assert_eq!(account.total, account.available + account.held);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_follows_lock_policy(){
+        for (policy, deposit) in [(LockPolicy::Transfers, true), (LockPolicy::Outgoing, true), (LockPolicy::Frozen, false)] {
+            let mut manager = TransactionManager::new(FeeSchedule::empty());
+            manager.set_lock_policy(policy);
+            manager.enable_audit();
+
+            manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(2.0))).await.unwrap();
+            manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+            manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await.unwrap();
+
+            let result = manager.process_transaction(&transaction(TransactionType::Deposit, 1, 2, Some(1.0))).await;
+            assert_eq!(result.is_ok(), deposit, "{}", policy);
+            assert!(manager.violations().is_empty(), "{}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_reports_each_account_once(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.enable_audit();
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(2.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        // A corrupted dispute record releases more than was held.
+        manager.accounts.get_mut(&client_id(1)).unwrap().transactions.get_mut(&tx_id(1)).unwrap().held = 3.0;
+
+        let resolve = Transaction {
+            destination: Some(client_id(1)),
+            ..transaction(TransactionType::Resolve, 1, 1, None)
+        };
+        manager.process_transaction(&resolve).await.unwrap();
+
+        assert_eq!(manager.violations().len(), 1);
+        let violation = &manager.violations()[0];
+        assert_eq!(violation.invariant, Invariant::HeldIsNotNegative);
+        assert_eq!(violation.tx, tx_id(1));
+        assert_eq!(violation.after.held, -1.0);
+    }
+
+    #[tokio::test]
+    async fn test_audit_disabled_records_nothing(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(2.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 2, Some(1.0))).await.unwrap();
+
+        assert!(manager.violations().is_empty());
+    }
+
+    async fn overdrawn_dispute(policy: DisputePolicy) -> (TransactionManager, Result<TransactionStatus, TransactionError>) {
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_dispute_policy(policy);
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(5.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(3.0))).await.unwrap();
+        let result = manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await;
+        (manager, result)
+    }
+
+    #[tokio::test]
+    async fn test_dispute_policy_allow_negative(){
+        let (manager, result) = overdrawn_dispute(DisputePolicy::AllowNegative).await;
+
+        assert_matches!(result, Ok(TransactionStatus::Applied));
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, -3.0);
+        assert_eq!(account.held, 5.0);
+        assert_eq!(account.overdrawn, 3.0);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_policy_reject(){
+        let (manager, result) = overdrawn_dispute(DisputePolicy::Reject).await;
+
+        assert_matches!(result, Err(TransactionError::InsufficientFundForDispute));
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 2.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.overdrawn, 0.0);
+        assert!(!account.transactions.get(&tx_id(1)).unwrap().disputed);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_policy_cap(){
+        let (mut manager, result) = overdrawn_dispute(DisputePolicy::Cap).await;
+
+        assert_matches!(result, Ok(TransactionStatus::Applied));
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 2.0);
+        assert_eq!(account.total, 2.0);
+        assert_eq!(account.overdrawn, 0.0);
+
+        manager.process_transaction(&transaction(TransactionType::Resolve, 1, 1, None)).await.unwrap();
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 2.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.overdrawn, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_dispute_policy_cap_chargeback(){
+        let (mut manager, _) = overdrawn_dispute(DisputePolicy::Cap).await;
+
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await.unwrap();
+
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert_eq!(account.overdrawn, 0.0);
+        assert!(account.locked);
+        assert_eq!(manager.ledger().balance(LedgerAccount::ChargebackLoss), 2.0);
+        assert_eq!(manager.ledger().trial_balance(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_overdrawn_follows_the_balance(){
+        for (policy, disputed, charged_back) in [
+            (DisputePolicy::AllowNegative, 3.0, 1.0),
+            (DisputePolicy::Reject, 0.0, 0.0),
+            (DisputePolicy::Cap, 0.0, 0.0),
+        ] {
+            let (mut manager, _) = overdrawn_dispute(policy).await;
+            assert_eq!(manager.accounts.get(&client_id(1)).unwrap().overdrawn, disputed, "{:?}", policy);
+
+            manager.process_transaction(&transaction(TransactionType::Deposit, 1, 3, Some(2.0))).await.unwrap();
+            assert_eq!(manager.accounts.get(&client_id(1)).unwrap().overdrawn, charged_back, "{:?}", policy);
+
+            // The chargeback takes the held funds, what the client still owes stays reported.
+            let _ = manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await;
+            let account = manager.accounts.get(&client_id(1)).unwrap();
+            assert_eq!(account.overdrawn, charged_back, "{:?}", policy);
+            assert_eq!(account.overdrawn, (-account.available).max(0.0), "{:?}", policy);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_risk_controls_reject_before_balance_change(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_risk_controls(RiskControls::new(vec![TierLimits {
+            tier: DEFAULT_TIER.to_owned(),
+            max_withdrawal: Some(5.0),
+            window: Some(3),
+            max_window_withdrawal: Some(8.0),
+            max_window_transactions: None,
+        }], Vec::new()));
+
+        let mut deposit = transaction(TransactionType::Deposit, 1, 1, Some(20.0));
+        deposit.sequence = 1;
+        manager.process_transaction(&deposit).await.unwrap();
+
+        let mut large = transaction(TransactionType::WithDrawal, 1, 2, Some(6.0));
+        large.sequence = 2;
+        assert_matches!(manager.process_transaction(&large).await, Err(TransactionError::WithdrawalLimitExceeded));
+
+        let mut first = transaction(TransactionType::WithDrawal, 1, 3, Some(5.0));
+        first.sequence = 3;
+        assert_matches!(manager.process_transaction(&first).await, Ok(TransactionStatus::Applied));
+
+        let mut second = transaction(TransactionType::WithDrawal, 1, 4, Some(5.0));
+        second.sequence = 4;
+        assert_matches!(manager.process_transaction(&second).await, Err(TransactionError::WindowWithdrawalLimitExceeded));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 15.0);
+
+        let mut later = transaction(TransactionType::WithDrawal, 1, 5, Some(5.0));
+        later.sequence = 6;
+        assert_matches!(manager.process_transaction(&later).await, Ok(TransactionStatus::Applied));
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().available, 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_transactions_do_not_advance_rules(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.add_rule(Box::new(DepositThenWithdrawal::new(5, Action::Block)));
+        manager.add_rule(Box::new(RepeatedChargebacks::new(2, Action::Block)));
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(10.0))).await.unwrap();
+        let mut no_amount = transaction(TransactionType::Deposit, 2, 2, None);
+        no_amount.sequence = 10;
+        assert_matches!(manager.process_transaction(&no_amount).await, Err(TransactionError::NoAmountForTransaction));
+        let mut withdrawal = transaction(TransactionType::WithDrawal, 2, 3, Some(1.0));
+        withdrawal.sequence = 11;
+        assert_matches!(manager.process_transaction(&withdrawal).await, Err(TransactionError::InsufficientFund));
+
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await, Err(TransactionError::ReferencedTransactionIsNotDisputed));
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await, Ok(TransactionStatus::Applied));
+
+        assert!(manager.alerts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rules_flag_and_block(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.add_rule(Box::new(DepositThenWithdrawal::new(5, Action::Flag)));
+        manager.add_rule(Box::new(RepeatedChargebacks::new(1, Action::Block)));
+
+        let mut deposit = transaction(TransactionType::Deposit, 1, 1, Some(10.0));
+        deposit.sequence = 1;
+        manager.process_transaction(&deposit).await.unwrap();
+        let mut withdrawal = transaction(TransactionType::WithDrawal, 1, 2, Some(4.0));
+        withdrawal.sequence = 2;
+        assert_matches!(manager.process_transaction(&withdrawal).await, Ok(TransactionStatus::Applied));
+
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await, Err(TransactionError::BlockedByRule("repeated_chargebacks")));
+
+        let account = manager.accounts.get(&client_id(1)).unwrap();
+        assert_eq!(account.available, -4.0);
+        assert_eq!(account.held, 10.0);
+        assert!(!account.locked);
+
+        assert_eq!(manager.alerts().len(), 2);
+        assert_eq!(manager.alerts()[0].rule, "deposit_withdrawal");
+        assert_eq!(manager.alerts()[0].action, Action::Flag);
+        assert_eq!(manager.alerts()[0].tx, tx_id(2));
+        assert_eq!(manager.alerts()[1].action, Action::Block);
+    }
+
+    #[tokio::test]
+    async fn test_last_activity(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+
+        let mut deposit = transaction(TransactionType::Deposit, 1, 1, Some(10.0));
+        deposit.timestamp = Some(1627992002000);
+        manager.process_transaction(&deposit).await.unwrap();
+        let mut late = transfer(1, Some(2), 2, Some(1.0));
+        late.timestamp = Some(1627992001000);
+        manager.process_transaction(&late).await.unwrap();
+        let mut rejected = transaction(TransactionType::WithDrawal, 1, 3, Some(100.0));
+        rejected.timestamp = Some(1627992009000);
+        assert!(manager.process_transaction(&rejected).await.is_err());
+
+        assert_eq!(manager.accounts.get(&client_id(1)).unwrap().activity().last_activity.as_deref(), Some("2021-08-03T12:00:02.000Z"));
+        assert_eq!(manager.accounts.get(&client_id(2)).unwrap().activity().last_activity.as_deref(), Some("2021-08-03T12:00:01.000Z"));
+    }
+
+    #[tokio::test]
+    async fn test_history(){
+        let mut manager = TransactionManager::new(fee_schedule());
+        manager.enable_history();
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(10.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(2.0))).await.unwrap();
+        assert!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 3, Some(20.0))).await.is_err());
+        manager.process_transaction(&transfer(1, Some(2), 4, Some(1.0))).await.unwrap();
+
+        let history = manager.history().unwrap();
+        assert_eq!(history.points().len(), 5);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(1))).unwrap().available, 10.0);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(2))).unwrap().available, 7.5);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(3))), None);
+        assert_eq!(history.as_of(client_id(1), AsOf::Tx(tx_id(4))).unwrap().available, 6.5);
+        assert_eq!(history.as_of(client_id(2), AsOf::Tx(tx_id(2))), None);
+        assert_eq!(history.as_of(client_id(2), AsOf::Tx(tx_id(4))).unwrap().available, 1.0);
+        assert_eq!(history.as_of(DEFAULT_HOUSE_ACCOUNT, AsOf::Tx(tx_id(2))).unwrap().available, 0.5);
+    }
+
+    #[tokio::test]
+    async fn test_events_rebuild_final_state(){
+        let mut manager = TransactionManager::new(fee_schedule());
+        manager.set_dispute_policy(DisputePolicy::Cap);
+        manager.enable_events();
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(10.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(4.0))).await.unwrap();
+        manager.process_transaction(&transfer(1, Some(2), 3, Some(1.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Resolve, 1, 1, None)).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Deposit, 2, 5, Some(1.0))).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::Dispute, 2, 3, None)).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 2, 3, None)).await.unwrap();
+        assert!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 4, Some(50.0))).await.is_err());
+
+        let events = manager.events().unwrap();
+        assert_eq!(events.len(), 9);
+        assert!(events.iter().all(|event| event.client != DEFAULT_HOUSE_ACCOUNT));
+        assert_eq!(events[4].dispute, DisputeState::Open);
+        assert_eq!(events[4].amount, Some(4.5));
+        assert_eq!(events[5].dispute, DisputeState::Resolved);
+
+        let mut accounts: Vec<AccountState> = manager.client_accounts().map(Account::state).collect();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(events::rebuild(events::per_client(events)), accounts);
+    }
+
+    #[tokio::test]
+    async fn test_events_rebuild_every_account(){
+        let mut manager = TransactionManager::new(fee_schedule());
+        manager.set_directory(Directory::new(vec![ClientRecord {
+            client: client_id(3), name: None, tier: None, status: Some(AccountStatus::Suspended), currency: None, max_withdrawal: None,
+        }], UnknownClientPolicy::Create));
+        manager.enable_events();
+
+        manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(10.0))).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 2, 2, Some(5.0))).await, Err(TransactionError::InsufficientFund));
+        manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 3, Some(2.0))).await.unwrap();
+
+        let events = manager.events().unwrap();
+        assert_eq!(events[0].client, client_id(3));
+        assert_eq!(events[0].tx, None);
+        let opened = events.iter().find(|event| event.client == client_id(2)).unwrap();
+        assert_eq!(opened.trans_type, TransactionType::Open);
+        assert_eq!(opened.tx, Some(tx_id(2)));
+
+        let mut accounts: Vec<AccountState> = manager.client_accounts().map(Account::state).collect();
+        accounts.sort_by_key(|account| account.client);
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(manager.accounts().len(), 4);
+        assert_eq!(events::rebuild(events::per_client(events)), accounts);
+    }
+
+    #[tokio::test]
+    async fn test_exact_duplicates_are_idempotent(){
+        let mut manager = TransactionManager::new(fee_schedule());
+        manager.enable_events();
+
+        let deposit = transaction(TransactionType::Deposit, 1, 1, Some(10.0));
+        let withdrawal = transaction(TransactionType::WithDrawal, 1, 2, Some(2.0));
+        let payment = transfer(1, Some(2), 3, Some(1.0));
+        let dispute = transaction(TransactionType::Dispute, 2, 3, None);
+        for transaction in [&deposit, &withdrawal, &payment, &dispute].iter() {
+            assert_matches!(manager.process_transaction(transaction).await, Ok(TransactionStatus::Applied));
+            assert_matches!(manager.process_transaction(transaction).await, Ok(TransactionStatus::Duplicate));
+        }
+
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 1, 1, Some(11.0))).await, Err(TransactionError::ExistingTransactionId));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 1, Some(10.0))).await, Err(TransactionError::ExistingTransactionId));
+        assert_matches!(manager.process_transaction(&transfer(1, Some(3), 3, Some(1.0))).await, Err(TransactionError::ExistingTransactionId));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Deposit, 2, 1, Some(10.0))).await, Err(TransactionError::ExistingTransactionId));
+
+        let client = &manager.accounts()[&client_id(1)];
+        assert_eq!(client.available, 6.5);
+        let receiver = &manager.accounts()[&client_id(2)];
+        assert_eq!(receiver.available, 0.0);
+        assert_eq!(receiver.held, 1.0);
+        assert_eq!(manager.accounts()[&DEFAULT_HOUSE_ACCOUNT].available, 0.5);
+        assert_eq!(manager.events().unwrap().len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_retried_dispute_outcomes_are_idempotent(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+
+        deposit(&mut manager, 1, 1, 10.0).await;
+        deposit(&mut manager, 1, 2, 5.0).await;
+        for (trans_type, tx) in [(TransactionType::Dispute, 1), (TransactionType::Resolve, 1), (TransactionType::Dispute, 2), (TransactionType::ChargeBack, 2)] {
+            let row = transaction(trans_type, 1, tx, None);
+            assert_matches!(manager.process_transaction(&row).await, Ok(TransactionStatus::Applied));
+            assert_matches!(manager.process_transaction(&row).await, Ok(TransactionStatus::Duplicate));
+        }
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await, Err(TransactionError::ReferencedTransactionIsNotDisputed));
+
+        // A resolved transaction can be disputed again, its next resolve is applied rather than taken for a retry.
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Resolve, 1, 1, None)).await, Ok(TransactionStatus::Applied));
+
+        let account = &manager.accounts()[&client_id(1)];
+        assert_eq!(account.available, 10.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(manager.ledger().balance(LedgerAccount::ChargebackLoss), 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_reversal(){
+        let mut manager = TransactionManager::new(fee_schedule());
+        manager.enable_events();
+
+        deposit(&mut manager, 1, 1, 10.0).await;
+        manager.process_transaction(&transaction(TransactionType::WithDrawal, 1, 2, Some(3.0))).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 2, None)).await, Ok(TransactionStatus::Applied));
+        assert_eq!(manager.accounts()[&client_id(1)].available, 9.5);
+
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 1, None)).await, Err(TransactionError::InsufficientFund));
+        deposit(&mut manager, 1, 3, 2.0).await;
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 1, None)).await, Ok(TransactionStatus::Applied));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 1, None)).await, Ok(TransactionStatus::Duplicate));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await, Err(TransactionError::TransactionNotDisputable));
+
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 3, None)).await.unwrap();
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 3, None)).await, Err(TransactionError::TransactionNotReversible));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 9, None)).await, Err(TransactionError::InvalidReferencedTransaction));
+
+        let account = &manager.accounts()[&client_id(1)];
+        assert_eq!(account.available, -0.5);
+        assert_eq!(account.held, 2.0);
+        assert!(!account.locked);
+        assert!(account.transactions[&tx_id(1)].reversed);
+        assert_eq!(manager.ledger().trial_balance(), 0.0);
+
+        let reversal = manager.events().unwrap().iter().find(|event| event.trans_type == TransactionType::Reversal && event.tx == Some(tx_id(1))).unwrap();
+        assert!(reversal.reversed);
+        assert_eq!(reversal.amount, Some(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_charged_back_deposit_is_final(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+
+        deposit(&mut manager, 1, 1, 10.0).await;
+        manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await.unwrap();
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await.unwrap();
+        deposit(&mut manager, 1, 2, 20.0).await;
+
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Reversal, 1, 1, None)).await, Err(TransactionError::TransactionNotReversible));
+        assert_matches!(manager.process_transaction(&transaction(TransactionType::Dispute, 1, 1, None)).await, Err(TransactionError::TransactionNotDisputable));
+
+        let account = &manager.accounts()[&client_id(1)];
+        assert_eq!(account.available, 20.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 20.0);
+        assert_eq!(manager.ledger().trial_balance(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_locked_accounts_counts_each_account_once(){
+        let mut manager = TransactionManager::new(FeeSchedule::empty());
+        manager.set_lock_policy(LockPolicy::Outgoing);
+
+        for (client, tx) in [(1, 1), (1, 2), (2, 3)] {
+            deposit(&mut manager, client, tx, 10.0).await;
+            manager.process_transaction(&transaction(TransactionType::Dispute, client, tx, None)).await.unwrap();
+        }
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 1, None)).await.unwrap();
+        assert_eq!(manager.locked_accounts(), 1);
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 1, 2, None)).await.unwrap();
+        assert_eq!(manager.locked_accounts(), 1);
+        manager.process_transaction(&transaction(TransactionType::ChargeBack, 2, 3, None)).await.unwrap();
+        assert_eq!(manager.locked_accounts(), 2);
+    }
+
+    // Random sequences over a few clients and a few ids, so that disputes, transfers and duplicates hit each other.
+    fn generated_transaction() -> impl Strategy<Value = Transaction> {
+        let types = prop_oneof![
+            Just(TransactionType::Deposit), Just(TransactionType::WithDrawal), Just(TransactionType::Dispute), Just(TransactionType::Resolve),
+            Just(TransactionType::ChargeBack), Just(TransactionType::Transfer), Just(TransactionType::Reversal),
+        ];
+        let amount = proptest::option::weighted(0.9, (0u32..=50_000).prop_map(|units| units as f64 / 10000.0));
+        (types, 1u64..=4, 1u64..=20, amount, proptest::option::of(1u64..=5))
+            .prop_map(|(trans_type, client, tx, amount, destination)| Transaction {
+                destination: destination.map(client_id),
+                ..transaction(trans_type, client, tx, amount)
+            })
+    }
+
+    fn run_generated(transactions: &[Transaction], policy: DisputePolicy) -> (TransactionManager, Vec<Result<TransactionStatus, TransactionError>>) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut manager = TransactionManager::new(fee_schedule());
+            manager.set_dispute_policy(policy);
+            manager.enable_audit();
+            let mut results = Vec::new();
+            for transaction in transactions {
+                results.push(manager.process_transaction(transaction).await);
+            }
+            (manager, results)
+        })
+    }
+
+    // Runs the transactions under audit with the locked account changes the audit has to report: a locked account
+    // refuses transfers and whatever else the lock policy refuses, a change through any of those is a violation.
+    fn run_audited(transactions: &[Transaction], lock_policy: LockPolicy) -> (TransactionManager, Vec<(Invariant, TxId, ClientId)>) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(async {
+            let mut manager = TransactionManager::new(fee_schedule());
+            manager.set_dispute_policy(DisputePolicy::Reject);
+            manager.set_lock_policy(lock_policy);
+            manager.enable_audit();
+            let mut expected = Vec::new();
+            for transaction in transactions {
+                let balances = |manager: &TransactionManager| manager.accounts().get(&transaction.client)
+                    .map(|account| (account.available, account.held, account.total, account.locked));
+                let before = balances(&manager);
+                let _ = manager.process_transaction(transaction).await;
+                let locked = before.is_some_and(|before| before.3);
+                let refused = transaction.trans_type == TransactionType::Transfer || lock_policy.refuses(transaction.trans_type);
+                if locked && refused && before != balances(&manager) {
+                    expected.push((Invariant::LockedAccountFollowsPolicy, transaction.tx, transaction.client));
+                }
+            }
+            (manager, expected)
+        })
+    }
+
+    fn states(manager: &TransactionManager) -> Vec<AccountState> {
+        let mut states: Vec<AccountState> = manager.accounts().values().map(Account::state).collect();
+        states.sort_by_key(|state| state.client);
+        states
+    }
+
+    fn is_zero(amount: f64) -> bool {
+        (amount * 10000.0).round() == 0.0
+    }
+
+    proptest! {
+        #[test]
+        fn prop_funds_are_conserved(transactions in proptest::collection::vec(generated_transaction(), 0..60), policy in prop_oneof![Just(DisputePolicy::AllowNegative), Just(DisputePolicy::Reject), Just(DisputePolicy::Cap)]) {
+            let (manager, _) = run_generated(&transactions, policy);
+            let ledger = manager.ledger();
+            prop_assert!(is_zero(ledger.trial_balance()));
+
+            let funds: f64 = manager.accounts().values().map(|account| account.total).sum();
+            prop_assert!(is_zero(funds + ledger.balance(LedgerAccount::Settlement) + ledger.balance(LedgerAccount::ChargebackLoss)));
+            prop_assert_eq!(manager.locked_accounts(), manager.accounts().values().filter(|account| account.locked()).count());
+            prop_assert_eq!(manager.client_account_count(), manager.client_accounts().count());
+        }
+
+        #[test]
+        fn prop_balance_invariants_hold(transactions in proptest::collection::vec(generated_transaction(), 0..60), lock_policy in prop_oneof![Just(LockPolicy::Transfers), Just(LockPolicy::Outgoing), Just(LockPolicy::Frozen)]) {
+            let (manager, expected) = run_audited(&transactions, lock_policy);
+            let violations: Vec<(Invariant, TxId, ClientId)> = manager.violations().iter()
+                .map(|violation| (violation.invariant, violation.tx, violation.after.client))
+                .collect();
+            prop_assert_eq!(violations, expected);
+            for account in manager.accounts().values() {
+                prop_assert!(is_zero(account.available - manager.ledger().balance(LedgerAccount::Available(account.id))));
+                prop_assert!(is_zero(account.held - manager.ledger().balance(LedgerAccount::Held(account.id))));
+                prop_assert!(is_zero(account.total - account.available - account.held));
+                prop_assert!(account.held >= 0.0);
+                prop_assert!(account.available >= 0.0 || account.id == DEFAULT_HOUSE_ACCOUNT || account.locked);
+            }
+        }
+
+        #[test]
+        fn prop_runs_are_deterministic(transactions in proptest::collection::vec(generated_transaction(), 0..60)) {
+            let (first, first_results) = run_generated(&transactions, DisputePolicy::AllowNegative);
+            let (second, second_results) = run_generated(&transactions, DisputePolicy::AllowNegative);
+            prop_assert_eq!(format!("{:?}", first_results), format!("{:?}", second_results));
+            prop_assert_eq!(states(&first), states(&second));
+            prop_assert_eq!(first.ledger().postings().len(), second.ledger().postings().len());
+        }
+    }
+}